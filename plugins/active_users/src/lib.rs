@@ -1,25 +1,36 @@
-use std::io::Error;
+use speculare_plugin::{declare_plugin, Plugin, PluginError, Value};
 use sys_metrics::host::get_logged_users;
 
-#[no_mangle]
-pub fn info() -> String {
-    String::from("active_users")
-}
+/// Report the users currently logged in.
+pub struct ActiveUsers;
+
+impl Plugin for ActiveUsers {
+    fn name(&self) -> &'static str {
+        "active_users"
+    }
 
-#[no_mangle]
-pub fn entrypoint() -> Result<String, Error> {
-    match serde_json::to_string(&get_logged_users()?) {
-        Ok(res_str) => Ok(res_str),
-        Err(serde_err) => Err(Error::from(serde_err)),
+    fn version(&self) -> &'static str {
+        env!("CARGO_PKG_VERSION")
+    }
+
+    fn collect(&mut self) -> Result<Value, PluginError> {
+        Ok(serde_json::to_value(get_logged_users()?)?)
     }
 }
 
+declare_plugin!(ActiveUsers);
+
 #[cfg(test)]
 mod tests {
-    use super::entrypoint;
+    use super::{speculare_plugin_collect, speculare_plugin_free};
+
+    use speculare_plugin::abi::STATUS_OK;
 
     #[test]
     fn get_users() {
-        entrypoint().unwrap();
+        let res = speculare_plugin_collect();
+        assert_eq!(res.status, STATUS_OK);
+        assert!(!res.data.is_null());
+        unsafe { speculare_plugin_free(res.data) };
     }
 }
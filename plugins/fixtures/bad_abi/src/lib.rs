@@ -0,0 +1,18 @@
+//! Invalid plugin used by the tests of the agent: built for another ABI version.
+use speculare_plugin::abi::ABI_VERSION;
+use std::os::raw::c_char;
+
+#[no_mangle]
+pub extern "C" fn speculare_plugin_abi_version() -> u32 {
+    ABI_VERSION + 1
+}
+
+#[no_mangle]
+pub extern "C" fn speculare_plugin_name() -> *const c_char {
+    b"bad_abi\0".as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub extern "C" fn speculare_plugin_version() -> *const c_char {
+    b"0.1.0\0".as_ptr() as *const c_char
+}
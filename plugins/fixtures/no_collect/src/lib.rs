@@ -0,0 +1,36 @@
+//! Invalid plugin used by the tests of the agent: speculare_plugin_collect is missing.
+use speculare_plugin::abi::{SpeculareResult, ABI_VERSION, STATUS_OK};
+use std::{os::raw::c_char, ptr};
+
+#[no_mangle]
+pub extern "C" fn speculare_plugin_abi_version() -> u32 {
+    ABI_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn speculare_plugin_name() -> *const c_char {
+    b"no_collect\0".as_ptr() as *const c_char
+}
+
+#[no_mangle]
+pub extern "C" fn speculare_plugin_version() -> *const c_char {
+    b"0.1.0\0".as_ptr() as *const c_char
+}
+
+/// # Safety
+/// Nothing is read from `settings`.
+#[no_mangle]
+pub unsafe extern "C" fn speculare_plugin_init(_settings: *const c_char) -> SpeculareResult {
+    SpeculareResult {
+        status: STATUS_OK,
+        data: ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn speculare_plugin_shutdown() {}
+
+/// # Safety
+/// Nothing is freed, the plugin never allocates.
+#[no_mangle]
+pub unsafe extern "C" fn speculare_plugin_free(_data: *mut c_char) {}
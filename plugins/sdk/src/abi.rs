@@ -0,0 +1,38 @@
+//! C ABI shared by the agent and the plugins, see plugins/speculare_plugin.h.
+
+use std::os::raw::c_char;
+
+/// Version of the plugin ABI, bumped on any breaking change of the symbols below.
+pub const ABI_VERSION: u32 = 2;
+
+pub const SYM_ABI_VERSION: &[u8] = b"speculare_plugin_abi_version\0";
+pub const SYM_NAME: &[u8] = b"speculare_plugin_name\0";
+pub const SYM_VERSION: &[u8] = b"speculare_plugin_version\0";
+pub const SYM_INIT: &[u8] = b"speculare_plugin_init\0";
+pub const SYM_COLLECT: &[u8] = b"speculare_plugin_collect\0";
+pub const SYM_SHUTDOWN: &[u8] = b"speculare_plugin_shutdown\0";
+pub const SYM_FREE: &[u8] = b"speculare_plugin_free\0";
+
+/// The call succeeded.
+pub const STATUS_OK: i32 = 0;
+/// The plugin returned an error.
+pub const STATUS_ERROR: i32 = 1;
+/// The plugin panicked (the panic was caught before crossing the ABI).
+pub const STATUS_PANIC: i32 = 2;
+
+/// Result of `speculare_plugin_init` and `speculare_plugin_collect`.
+#[repr(C)]
+pub struct SpeculareResult {
+    // STATUS_OK and data is the JSON result (or null), or data is the error message
+    pub status: i32,
+    // NUL terminated UTF-8 owned by the plugin, released with speculare_plugin_free
+    pub data: *mut c_char,
+}
+
+pub type AbiVersionFn = unsafe extern "C" fn() -> u32;
+pub type NameFn = unsafe extern "C" fn() -> *const c_char;
+pub type VersionFn = unsafe extern "C" fn() -> *const c_char;
+pub type InitFn = unsafe extern "C" fn(*const c_char) -> SpeculareResult;
+pub type CollectFn = unsafe extern "C" fn() -> SpeculareResult;
+pub type ShutdownFn = unsafe extern "C" fn();
+pub type FreeFn = unsafe extern "C" fn(*mut c_char);
@@ -0,0 +1,134 @@
+//! Implementation of the exported symbols, used by `declare_plugin!`.
+
+use crate::{
+    abi::{SpeculareResult, STATUS_ERROR, STATUS_OK, STATUS_PANIC},
+    Plugin,
+};
+
+use std::{
+    any::Any,
+    ffi::{CStr, CString},
+    os::raw::c_char,
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr,
+    sync::{Mutex, MutexGuard, OnceLock, PoisonError},
+};
+
+/// Constructor of the plugin, generated by the macro.
+pub type Constructor = fn() -> Box<dyn Plugin>;
+
+// A plugin library holds a single plugin, so the state can be global
+static PLUGIN: Mutex<Option<Box<dyn Plugin>>> = Mutex::new(None);
+static NAME: OnceLock<CString> = OnceLock::new();
+static VERSION: OnceLock<CString> = OnceLock::new();
+
+fn to_c_string(value: String) -> *mut c_char {
+    // Interior NUL bytes can't cross the ABI, drop them
+    CString::new(value.replace('\0', "")).map_or(ptr::null_mut(), CString::into_raw)
+}
+
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(panic) => match panic.downcast::<&str>() {
+            Ok(msg) => (*msg).to_owned(),
+            Err(_) => "unknown panic".to_owned(),
+        },
+    }
+}
+
+/// Lock the plugin, creating it if needed (a panicking call doesn't poison it).
+fn plugin(new: Constructor) -> MutexGuard<'static, Option<Box<dyn Plugin>>> {
+    let mut guard = PLUGIN.lock().unwrap_or_else(PoisonError::into_inner);
+    if guard.is_none() {
+        *guard = Some(new());
+    }
+    guard
+}
+
+/// Run `call` against the plugin, catching its errors and panics.
+fn call(
+    new: Constructor,
+    call: impl FnOnce(&mut dyn Plugin) -> Result<Option<String>, String>,
+) -> SpeculareResult {
+    let res = catch_unwind(AssertUnwindSafe(|| {
+        let mut guard = plugin(new);
+        call(guard.as_deref_mut().expect("plugin is created"))
+    }));
+    let (status, data) = match res {
+        Ok(Ok(data)) => (STATUS_OK, data),
+        Ok(Err(err)) => (STATUS_ERROR, Some(err)),
+        Err(panic) => (STATUS_PANIC, Some(panic_message(panic))),
+    };
+    SpeculareResult {
+        status,
+        data: data.map_or(ptr::null_mut(), to_c_string),
+    }
+}
+
+/// Get a static C string, computed once from the plugin.
+fn static_str(
+    cell: &'static OnceLock<CString>,
+    new: Constructor,
+    get: fn(&dyn Plugin) -> &'static str,
+) -> *const c_char {
+    cell.get_or_init(|| {
+        let value = catch_unwind(AssertUnwindSafe(|| {
+            get(plugin(new).as_deref().expect("plugin is created"))
+        }))
+        .unwrap_or("unknown");
+        CString::new(value.replace('\0', "")).unwrap_or_default()
+    })
+    .as_ptr()
+}
+
+pub fn name(new: Constructor) -> *const c_char {
+    static_str(&NAME, new, |plugin| plugin.name())
+}
+
+pub fn version(new: Constructor) -> *const c_char {
+    static_str(&VERSION, new, |plugin| plugin.version())
+}
+
+/// # Safety
+/// `settings` must be null or point to a NUL terminated string.
+pub unsafe fn init(new: Constructor, settings: *const c_char) -> SpeculareResult {
+    let settings = if settings.is_null() {
+        Ok(serde_json::Value::Null)
+    } else {
+        serde_json::from_slice(CStr::from_ptr(settings).to_bytes())
+    };
+    call(new, |plugin| {
+        let settings = settings.map_err(|err| format!("invalid settings: {}", err))?;
+        plugin
+            .init(&settings)
+            .map(|_| None)
+            .map_err(|err| err.to_string())
+    })
+}
+
+pub fn collect(new: Constructor) -> SpeculareResult {
+    call(new, |plugin| {
+        plugin
+            .collect()
+            .map(|value| Some(value.to_string()))
+            .map_err(|err| err.to_string())
+    })
+}
+
+pub fn shutdown() {
+    let _ = catch_unwind(|| {
+        let plugin = PLUGIN.lock().unwrap_or_else(PoisonError::into_inner).take();
+        if let Some(mut plugin) = plugin {
+            plugin.shutdown();
+        }
+    });
+}
+
+/// # Safety
+/// `data` must be null or come from a SpeculareResult, and be freed only once.
+pub unsafe fn free(data: *mut c_char) {
+    if !data.is_null() {
+        drop(CString::from_raw(data));
+    }
+}
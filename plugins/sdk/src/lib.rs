@@ -0,0 +1,152 @@
+//! SDK to write Speculare client plugins in Rust.
+//!
+//! Implement [`Plugin`] and export it with [`declare_plugin!`], the macro generates
+//! the C symbols expected by the agent, checks the ABI version and catches the
+//! panics so they never cross the library boundary.
+//!
+//! `collect` returns any JSON value (number, string, map, ...) which is sent as is
+//! in the payload, or a list of [`Metric`] with their type and unit.
+//!
+//! ```ignore
+//! use speculare_plugin::{declare_plugin, metrics, Metric, Plugin, PluginError, Value};
+//!
+//! struct Hello;
+//!
+//! impl Plugin for Hello {
+//!     fn name(&self) -> &'static str {
+//!         "hello"
+//!     }
+//!
+//!     fn version(&self) -> &'static str {
+//!         env!("CARGO_PKG_VERSION")
+//!     }
+//!
+//!     fn collect(&mut self) -> Result<Value, PluginError> {
+//!         Ok(metrics(vec![
+//!             Metric::counter("greetings", 42),
+//!             Metric::gauge("latency", 1.5).with_unit("ms"),
+//!         ]))
+//!     }
+//! }
+//!
+//! declare_plugin!(Hello);
+//! ```
+
+pub mod abi;
+#[doc(hidden)]
+pub mod export;
+pub mod metric;
+
+pub use metric::{metrics, Metric, MetricKind};
+pub use serde_json::Value;
+
+pub type PluginError = Box<dyn std::error::Error>;
+
+/// A plugin collecting metrics for the agent.
+///
+/// The plugin is created on first use and lives until the agent unloads it.
+pub trait Plugin: Send {
+    /// Name of the plugin, used as its key in the payload.
+    fn name(&self) -> &'static str;
+
+    /// Version of the plugin.
+    fn version(&self) -> &'static str;
+
+    /// Called once after loading, with the settings of the plugin (null if none).
+    fn init(&mut self, _settings: &Value) -> Result<(), PluginError> {
+        Ok(())
+    }
+
+    /// Collect the metrics.
+    fn collect(&mut self) -> Result<Value, PluginError>;
+
+    /// Called before the plugin is unloaded.
+    fn shutdown(&mut self) {}
+}
+
+/// Export the plugin built by `$constructor` through the C ABI of the agent.
+#[macro_export]
+macro_rules! declare_plugin {
+    ($constructor:expr) => {
+        fn __speculare_plugin_new() -> Box<dyn $crate::Plugin> {
+            Box::new($constructor)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn speculare_plugin_abi_version() -> u32 {
+            $crate::abi::ABI_VERSION
+        }
+
+        #[no_mangle]
+        pub extern "C" fn speculare_plugin_name() -> *const ::std::os::raw::c_char {
+            $crate::export::name(__speculare_plugin_new)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn speculare_plugin_version() -> *const ::std::os::raw::c_char {
+            $crate::export::version(__speculare_plugin_new)
+        }
+
+        /// # Safety
+        /// `settings` must be null or point to a NUL terminated string.
+        #[no_mangle]
+        pub unsafe extern "C" fn speculare_plugin_init(
+            settings: *const ::std::os::raw::c_char,
+        ) -> $crate::abi::SpeculareResult {
+            $crate::export::init(__speculare_plugin_new, settings)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn speculare_plugin_collect() -> $crate::abi::SpeculareResult {
+            $crate::export::collect(__speculare_plugin_new)
+        }
+
+        #[no_mangle]
+        pub extern "C" fn speculare_plugin_shutdown() {
+            $crate::export::shutdown()
+        }
+
+        /// # Safety
+        /// `data` must be null or come from a SpeculareResult, and be freed only once.
+        #[no_mangle]
+        pub unsafe extern "C" fn speculare_plugin_free(data: *mut ::std::os::raw::c_char) {
+            $crate::export::free(data)
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{abi::STATUS_PANIC, Plugin, PluginError};
+
+    struct Panicking;
+
+    impl Plugin for Panicking {
+        fn name(&self) -> &'static str {
+            "panicking"
+        }
+
+        fn version(&self) -> &'static str {
+            "0.1.0"
+        }
+
+        fn collect(&mut self) -> Result<super::Value, PluginError> {
+            panic!("boom")
+        }
+    }
+
+    declare_plugin!(Panicking);
+
+    #[test]
+    fn panics_are_caught() {
+        let res = speculare_plugin_collect();
+        assert_eq!(res.status, STATUS_PANIC);
+        let msg = unsafe { std::ffi::CStr::from_ptr(res.data) }
+            .to_str()
+            .unwrap()
+            .to_owned();
+        unsafe { speculare_plugin_free(res.data) };
+        assert_eq!(msg, "boom");
+        assert_eq!(speculare_plugin_abi_version(), super::abi::ABI_VERSION);
+    }
+}
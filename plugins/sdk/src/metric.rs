@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// How a metric evolves, so the consumers know how to aggregate it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricKind {
+    // Value at the time of the collect (temperature, queue size, ...)
+    Gauge,
+    // Monotonic total since some point (requests served, bytes read, ...)
+    Counter,
+}
+
+/// A named value returned by a plugin, a list of them is serialized as
+/// `[{"name": ..., "type": ..., "value": ..., "unit": ...}, ...]`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metric {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: MetricKind,
+    pub value: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub unit: Option<String>,
+}
+
+impl Metric {
+    pub fn gauge(name: &str, value: impl Into<Value>) -> Self {
+        Metric {
+            name: name.to_owned(),
+            kind: MetricKind::Gauge,
+            value: value.into(),
+            unit: None,
+        }
+    }
+
+    pub fn counter(name: &str, value: impl Into<Value>) -> Self {
+        Metric {
+            kind: MetricKind::Counter,
+            ..Metric::gauge(name, value)
+        }
+    }
+
+    /// Set the unit of the metric (bytes, ms, %, ...).
+    pub fn with_unit(mut self, unit: &str) -> Self {
+        self.unit = Some(unit.to_owned());
+        self
+    }
+}
+
+/// Convert a list of metrics to the Value returned by collect.
+pub fn metrics(metrics: Vec<Metric>) -> Value {
+    serde_json::to_value(metrics).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{metrics, Metric};
+
+    use serde_json::json;
+
+    #[test]
+    fn serialize_metrics() {
+        let value = metrics(vec![
+            Metric::counter("requests", 42),
+            Metric::gauge("latency", 1.5).with_unit("ms"),
+        ]);
+        assert_eq!(
+            value,
+            json!([
+                {"name": "requests", "type": "counter", "value": 42},
+                {"name": "latency", "type": "gauge", "value": 1.5, "unit": "ms"}
+            ])
+        );
+    }
+}
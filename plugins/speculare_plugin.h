@@ -0,0 +1,53 @@
+/*
+ * Speculare plugin ABI.
+ *
+ * A plugin is a shared library (.so/.dylib) placed in the plugins_path of the
+ * config and exporting the functions below with the C calling convention.
+ * The agent refuses plugins whose speculare_plugin_abi_version() differs from
+ * the version it was built with.
+ *
+ * Rust plugins should use the SDK (plugins/sdk) which generates them.
+ */
+#ifndef SPECULARE_PLUGIN_H
+#define SPECULARE_PLUGIN_H
+
+#include <stdint.h>
+
+#define SPECULARE_PLUGIN_ABI_VERSION 2
+
+#define SPECULARE_STATUS_OK 0
+#define SPECULARE_STATUS_ERROR 1
+#define SPECULARE_STATUS_PANIC 2
+
+typedef struct {
+    /*
+     * SPECULARE_STATUS_OK and data is the JSON result (or NULL), or data is the error message.
+     * The result can be any JSON value, or a list of metrics:
+     * [{"name": "requests", "type": "counter", "value": 42, "unit": "req"}, ...]
+     * where type is gauge or counter and unit is optional.
+     */
+    int32_t status;
+    /* NUL terminated UTF-8 string owned by the plugin, released with speculare_plugin_free */
+    char *data;
+} SpeculareResult;
+
+/* Must return SPECULARE_PLUGIN_ABI_VERSION */
+uint32_t speculare_plugin_abi_version(void);
+
+/* Name and version of the plugin, static strings which are never freed */
+const char *speculare_plugin_name(void);
+const char *speculare_plugin_version(void);
+
+/* Called once after loading, with the settings of the plugin as JSON */
+SpeculareResult speculare_plugin_init(const char *settings);
+
+/* Collect the metrics, called every harvest */
+SpeculareResult speculare_plugin_collect(void);
+
+/* Called before the plugin is unloaded */
+void speculare_plugin_shutdown(void);
+
+/* Release the data of a SpeculareResult */
+void speculare_plugin_free(char *data);
+
+#endif /* SPECULARE_PLUGIN_H */
@@ -0,0 +1,5 @@
+pub mod notify;
+pub use self::notify::*;
+
+pub mod rules;
+pub use self::rules::*;
@@ -0,0 +1,80 @@
+use super::rules::AlertEvent;
+
+use hyper::{client::HttpConnector, Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use std::{process::Command, thread};
+
+/// Deliver the alert events to a webhook and/or a local command.
+pub struct Notifier {
+    webhook: Option<String>,
+    command: Option<String>,
+    client: Client<HttpsConnector<HttpConnector>>,
+}
+
+impl Notifier {
+    pub fn new(webhook: Option<String>, command: Option<String>) -> Self {
+        // Webhooks are often local (chat bots, ...) so plain http is allowed
+        Notifier {
+            webhook,
+            command,
+            client: Client::builder().build::<_, Body>(HttpsConnector::new()),
+        }
+    }
+
+    /// Send each event without waiting for the delivery to complete.
+    pub fn notify(&self, events: Vec<AlertEvent>) {
+        for event in events {
+            let body = match serde_json::to_string(&event) {
+                Ok(body) => body,
+                Err(err) => {
+                    error!("alerts: cannot serialize the event: {}", err);
+                    continue;
+                }
+            };
+
+            if let Some(webhook) = &self.webhook {
+                let request = Request::builder()
+                    .method(Method::POST)
+                    .uri(webhook)
+                    .header("content-type", "application/json")
+                    .body(Body::from(body.clone()));
+                match request {
+                    Ok(request) => {
+                        let client = self.client.clone();
+                        tokio::spawn(async move {
+                            match client.request(request).await {
+                                Ok(resp) if resp.status().is_success() => {
+                                    trace!("alerts: webhook answered {}", resp.status())
+                                }
+                                Ok(resp) => error!("alerts: webhook answered {}", resp.status()),
+                                Err(err) => error!("alerts: webhook failed: {}", err),
+                            }
+                        });
+                    }
+                    Err(err) => error!("alerts: cannot build the webhook request: {}", err),
+                }
+            }
+
+            if let Some(command) = &self.command {
+                let command = command.to_owned();
+                let name = event.name.to_owned();
+                let status = format!("{:?}", event.status).to_lowercase();
+                // Run in its own thread so that a slow command doesn't block the harvest
+                thread::spawn(move || {
+                    let res = Command::new("sh")
+                        .arg("-c")
+                        .arg(&command)
+                        .env("SPECULARE_ALERT", &body)
+                        .env("SPECULARE_ALERT_NAME", &name)
+                        .env("SPECULARE_ALERT_STATUS", &status)
+                        .status();
+                    match res {
+                        Ok(exit) if exit.success() => {}
+                        Ok(exit) => error!("alerts: `{}` exited with {}", command, exit),
+                        Err(err) => error!("alerts: cannot run `{}`: {}", command, err),
+                    }
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,526 @@
+use crate::harvest::data_harvest::{item_id, Data};
+use crate::options::AlertRule;
+
+use chrono::prelude::{DateTime, Utc};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Gt,
+    Ge,
+    Lt,
+    Le,
+    Eq,
+    Ne,
+    Matches,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+/// Notification of an alert changing state.
+#[derive(Debug, Clone, Serialize)]
+pub struct AlertEvent {
+    pub name: String,
+    // Element of the metric which triggered the alert (ex: disks./home.avail_space)
+    pub instance: String,
+    pub status: AlertStatus,
+    pub value: Value,
+    pub threshold: Value,
+    pub uuid: String,
+    pub hostname: String,
+    pub labels: BTreeMap<String, String>,
+    pub at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    Pending(DateTime<Utc>),
+    Firing,
+}
+
+struct CompiledRule {
+    name: String,
+    metric: String,
+    // Path of the collection holding the instances (disks, load_avg, ...)
+    collection: String,
+    ratio_of: Option<String>,
+    op: Op,
+    threshold: Value,
+    regex: Option<Regex>,
+    recover: Option<f64>,
+    for_secs: i64,
+}
+
+/// Evaluate the alert rules against each Data and keep track of their state.
+pub struct AlertEngine {
+    rules: Vec<CompiledRule>,
+    states: HashMap<(usize, String), State>,
+    // Consecutive samples an instance with a state was missing from
+    missed: HashMap<(usize, String), u32>,
+}
+
+/// Samples an instance can be missing from, while its collection is too, before
+/// its state is dropped (plugin unloaded, collector disabled, ...).
+const MAX_MISSED_SAMPLES: u32 = 100;
+
+fn walk<'a>(
+    value: &'a Value,
+    segments: &[&str],
+    instance: String,
+    keys: Vec<String>,
+    out: &mut Vec<(String, Vec<String>, &'a Value)>,
+) {
+    let (segment, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => {
+            out.push((instance, keys, value));
+            return;
+        }
+    };
+    let join = |id: &str| {
+        if instance.is_empty() {
+            id.to_owned()
+        } else {
+            format!("{}.{}", instance, id)
+        }
+    };
+
+    match value {
+        Value::Object(map) => {
+            if let Some(child) = map.get(*segment) {
+                walk(child, rest, join(segment), keys, out);
+            }
+        }
+        // Elements can be selected with *, their index or their name
+        Value::Array(items) => {
+            for (idx, item) in items.iter().enumerate() {
+                let id = item_id(item, idx);
+                if *segment == "*" || *segment == id || segment.parse::<usize>() == Ok(idx) {
+                    let mut keys = keys.clone();
+                    keys.push(id.to_owned());
+                    walk(item, rest, join(&id), keys, out);
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Resolve a dot separated `path` against `value` and return each (instance, names of
+/// the array elements it's in, value) found.
+fn resolve_keyed<'a>(value: &'a Value, path: &str) -> Vec<(String, Vec<String>, &'a Value)> {
+    let segments: Vec<&str> = path.split('.').collect();
+    let mut out = Vec::new();
+    walk(value, &segments, String::new(), Vec::new(), &mut out);
+    out
+}
+
+/// Resolve a dot separated `path` against `value` and return each (instance, value) found.
+pub fn resolve_path<'a>(value: &'a Value, path: &str) -> Vec<(String, &'a Value)> {
+    resolve_keyed(value, path)
+        .into_iter()
+        .map(|(instance, _, value)| (instance, value))
+        .collect()
+}
+
+/// Path of the collection holding the instances of `metric`: up to its last
+/// wildcard, or its parent if it has none.
+fn collection_of(metric: &str) -> String {
+    let segments: Vec<&str> = metric.split('.').collect();
+    let end = segments
+        .iter()
+        .rposition(|segment| *segment == "*")
+        .unwrap_or_else(|| segments.len().saturating_sub(1));
+    segments[..end].join(".")
+}
+
+impl Op {
+    fn parse(op: &str) -> Option<Op> {
+        Some(match op {
+            ">" => Op::Gt,
+            ">=" => Op::Ge,
+            "<" => Op::Lt,
+            "<=" => Op::Le,
+            "==" => Op::Eq,
+            "!=" => Op::Ne,
+            "matches" => Op::Matches,
+            _ => return None,
+        })
+    }
+
+    /// Check if `value` satisfies the condition against `threshold`.
+    fn check(self, value: &Value, threshold: &Value, regex: Option<&Regex>) -> bool {
+        match self {
+            Op::Eq => value == threshold,
+            Op::Ne => value != threshold,
+            Op::Matches => {
+                let text = match value {
+                    Value::String(text) => text.to_owned(),
+                    other => other.to_string(),
+                };
+                matches!(regex, Some(regex) if regex.is_match(&text))
+            }
+            _ => match (value.as_f64(), threshold.as_f64()) {
+                (Some(value), Some(threshold)) => match self {
+                    Op::Gt => value > threshold,
+                    Op::Ge => value >= threshold,
+                    Op::Lt => value < threshold,
+                    _ => value <= threshold,
+                },
+                _ => false,
+            },
+        }
+    }
+}
+
+impl CompiledRule {
+    /// Check if a firing alert can be resolved, taking the hysteresis into account.
+    fn recovered(&self, value: &Value) -> bool {
+        match (self.op, self.recover, value.as_f64()) {
+            (Op::Gt | Op::Ge, Some(recover), Some(value)) => value < recover,
+            (Op::Lt | Op::Le, Some(recover), Some(value)) => value > recover,
+            _ => !self.op.check(value, &self.threshold, self.regex.as_ref()),
+        }
+    }
+
+    /// Get the values of the metric, as a percentage of ratio_of if any.
+    fn values(&self, data: &Value) -> Vec<(String, Value)> {
+        let metrics = resolve_keyed(data, &self.metric);
+        let ratio_of = match &self.ratio_of {
+            Some(ratio_of) => ratio_of,
+            None => {
+                return metrics
+                    .into_iter()
+                    .map(|(instance, _, value)| (instance, value.clone()))
+                    .collect()
+            }
+        };
+
+        // Pair the values with the total of the same elements (disks./home with
+        // disks./home), or with the only total if there's just one.
+        let totals = resolve_keyed(data, ratio_of);
+        let total_of = |keys: &Vec<String>| match totals.as_slice() {
+            [(_, _, total)] => Some(*total),
+            totals => totals
+                .iter()
+                .find(|(_, total_keys, _)| total_keys == keys)
+                .map(|(_, _, total)| *total),
+        };
+        metrics
+            .into_iter()
+            .filter_map(|(instance, keys, value)| {
+                match (value.as_f64(), total_of(&keys).and_then(Value::as_f64)) {
+                    (Some(value), Some(total)) if total != 0.0 => {
+                        Some((instance, Value::from(value / total * 100.0)))
+                    }
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+}
+
+impl AlertEngine {
+    /// Compile the rules, invalid ones are skipped.
+    pub fn new(rules: &[AlertRule]) -> Self {
+        let rules = rules
+            .iter()
+            .filter_map(|rule| {
+                let op = match Op::parse(&rule.op) {
+                    Some(op) => op,
+                    None => {
+                        error!("alert {}: unknown op {}", rule.name, rule.op);
+                        return None;
+                    }
+                };
+                let regex = match (op, rule.threshold.as_str()) {
+                    (Op::Matches, Some(pattern)) => match Regex::new(pattern) {
+                        Ok(regex) => Some(regex),
+                        Err(err) => {
+                            error!("alert {}: invalid regex: {}", rule.name, err);
+                            return None;
+                        }
+                    },
+                    (Op::Matches, None) => {
+                        error!("alert {}: matches requires a string threshold", rule.name);
+                        return None;
+                    }
+                    _ => None,
+                };
+
+                Some(CompiledRule {
+                    name: rule.name.to_owned(),
+                    metric: rule.metric.to_owned(),
+                    collection: collection_of(&rule.metric),
+                    ratio_of: rule.ratio_of.to_owned(),
+                    op,
+                    threshold: rule.threshold.clone(),
+                    regex,
+                    recover: rule.recover,
+                    for_secs: rule.for_secs.unwrap_or(0),
+                })
+            })
+            .collect();
+
+        AlertEngine {
+            rules,
+            states: HashMap::new(),
+            missed: HashMap::new(),
+        }
+    }
+
+    /// Evaluate the rules against `data` and return the alerts which changed state.
+    pub fn evaluate(&mut self, data: &Data) -> Vec<AlertEvent> {
+        let value = match serde_json::to_value(data) {
+            Ok(value) => value,
+            Err(err) => {
+                error!("alerts: cannot convert the Data: {}", err);
+                return Vec::new();
+            }
+        };
+        let mut events = self.evaluate_value(&value, data.created_at);
+        for event in &mut events {
+            event.uuid = data.uuid.to_owned();
+            event.hostname = data.hostname.to_owned();
+            event.labels = data.labels.clone();
+        }
+        events
+    }
+
+    /// Evaluate the rules against `data` (a serialized Data) collected at `now`.
+    fn evaluate_value(&mut self, data: &Value, now: DateTime<Utc>) -> Vec<AlertEvent> {
+        let mut events = Vec::new();
+        let mut seen = HashSet::new();
+        let event = |rule: &CompiledRule, instance: String, status, value| {
+            info!("alert {} ({}) is now {:?}", rule.name, instance, status);
+            AlertEvent {
+                name: rule.name.to_owned(),
+                instance,
+                status,
+                value,
+                threshold: rule.threshold.clone(),
+                uuid: String::new(),
+                hostname: String::new(),
+                labels: BTreeMap::new(),
+                at: now,
+            }
+        };
+
+        for (idx, rule) in self.rules.iter().enumerate() {
+            for (instance, value) in rule.values(data) {
+                let key = (idx, instance);
+                seen.insert(key.clone());
+                self.missed.remove(&key);
+                let state = self.states.get(&key).copied();
+                let status = match state {
+                    Some(State::Firing) => {
+                        if !rule.recovered(&value) {
+                            continue;
+                        }
+                        self.states.remove(&key);
+                        AlertStatus::Resolved
+                    }
+                    _ if !rule.op.check(&value, &rule.threshold, rule.regex.as_ref()) => {
+                        self.states.remove(&key);
+                        continue;
+                    }
+                    Some(State::Pending(since)) => {
+                        if (now - since).num_seconds() < rule.for_secs {
+                            continue;
+                        }
+                        self.states.insert(key.clone(), State::Firing);
+                        AlertStatus::Firing
+                    }
+                    None if rule.for_secs > 0 => {
+                        self.states.insert(key, State::Pending(now));
+                        continue;
+                    }
+                    None => {
+                        self.states.insert(key.clone(), State::Firing);
+                        AlertStatus::Firing
+                    }
+                };
+                events.push(event(rule, key.1, status, value));
+            }
+        }
+
+        // The instance is gone (unmounted disk, removed interface, ...) if its collection
+        // is there without it: resolve its alert. A collection missing from the sample
+        // is only gathered some harvests (load_avg, sockets, slow plugins, ...).
+        let rules = &self.rules;
+        let missed = &mut self.missed;
+        self.states.retain(|(idx, instance), state| {
+            let key = (*idx, instance.to_owned());
+            if seen.contains(&key) {
+                return true;
+            }
+            let collection = &rules[*idx].collection;
+            let gathered = collection.is_empty()
+                || resolve_path(data, collection)
+                    .iter()
+                    .any(|(_, value)| !value.is_null());
+            if !gathered {
+                let count = missed.entry(key.clone()).or_insert(0);
+                *count += 1;
+                if *count < MAX_MISSED_SAMPLES {
+                    return true;
+                }
+            }
+            missed.remove(&key);
+            if *state == State::Firing {
+                events.push(event(
+                    &rules[*idx],
+                    instance.to_owned(),
+                    AlertStatus::Resolved,
+                    Value::Null,
+                ));
+            }
+            false
+        });
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve_path, AlertEngine, AlertStatus, Op};
+    use crate::options::AlertRule;
+
+    use chrono::{prelude::Utc, Duration};
+    use serde_json::json;
+
+    #[test]
+    fn resolve_and_check() {
+        let data = json!({
+            "swap": {"used": 512, "total": 1024},
+            "disks": [
+                {"mount_point": "/", "avail_space": 5, "total_space": 100},
+                {"mount_point": "/home", "avail_space": 50, "total_space": 100}
+            ],
+            "plugins": [{"key": "active_users", "val": "root"}]
+        });
+
+        let swap = resolve_path(&data, "swap.used");
+        assert_eq!(swap[0].0, "swap.used");
+        assert!(Op::Gt.check(swap[0].1, &json!(256), None));
+
+        let disks = resolve_path(&data, "disks.*.avail_space");
+        assert_eq!(disks.len(), 2);
+        assert_eq!(disks[1].0, "disks./home.avail_space");
+        assert_eq!(resolve_path(&data, "disks./.avail_space")[0].1, &json!(5));
+
+        let users = resolve_path(&data, "plugins.active_users.val");
+        let regex = regex::Regex::new("^root$").unwrap();
+        assert!(Op::Matches.check(users[0].1, &json!("^root$"), Some(&regex)));
+        assert!(resolve_path(&data, "memory.free").is_empty());
+    }
+
+    #[test]
+    fn alert_lifecycle() {
+        let rule = |for_secs, recover| AlertRule {
+            name: "disk_full".to_owned(),
+            metric: "disks.*.avail_space".to_owned(),
+            ratio_of: Some("disks.*.total_space".to_owned()),
+            op: "<".to_owned(),
+            threshold: json!(10),
+            recover,
+            for_secs,
+        };
+        let disks = |root: i64, home: Option<i64>| {
+            let mut disks =
+                vec![json!({"mount_point": "/", "avail_space": root, "total_space": 100})];
+            if let Some(home) = home {
+                disks
+                    .push(json!({"mount_point": "/home", "avail_space": home, "total_space": 100}));
+            }
+            json!({ "disks": disks })
+        };
+        let start = Utc::now();
+        let at = |secs| start + Duration::seconds(secs);
+
+        // Pending for for_secs, then Firing, then Resolved past the hysteresis only
+        let mut engine = AlertEngine::new(&[rule(Some(60), Some(20.0))]);
+        assert!(engine.evaluate_value(&disks(5, None), at(0)).is_empty());
+        assert!(engine.evaluate_value(&disks(5, None), at(30)).is_empty());
+        let events = engine.evaluate_value(&disks(5, None), at(60));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].instance, "disks./.avail_space");
+        assert_eq!(events[0].status, AlertStatus::Firing);
+        assert!(engine.evaluate_value(&disks(5, None), at(70)).is_empty());
+        assert!(engine.evaluate_value(&disks(15, None), at(80)).is_empty());
+        let events = engine.evaluate_value(&disks(25, None), at(90));
+        assert_eq!(events[0].status, AlertStatus::Resolved);
+
+        // Recovering while pending restarts the for_secs delay
+        assert!(engine.evaluate_value(&disks(5, None), at(100)).is_empty());
+        assert!(engine.evaluate_value(&disks(50, None), at(130)).is_empty());
+        assert!(engine.evaluate_value(&disks(5, None), at(170)).is_empty());
+        assert_eq!(engine.evaluate_value(&disks(5, None), at(230)).len(), 1);
+
+        // A firing instance which disappears is resolved
+        let mut engine = AlertEngine::new(&[rule(None, None)]);
+        let events = engine.evaluate_value(&disks(50, Some(5)), at(0));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].instance, "disks./home.avail_space");
+        let events = engine.evaluate_value(&disks(50, None), at(10));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].instance, "disks./home.avail_space");
+        assert_eq!(events[0].status, AlertStatus::Resolved);
+        assert!(engine.states.is_empty());
+    }
+
+    #[test]
+    fn sparse_metrics() {
+        let rule = AlertRule {
+            name: "load".to_owned(),
+            metric: "load_avg.one".to_owned(),
+            ratio_of: None,
+            op: ">".to_owned(),
+            threshold: json!(2),
+            recover: None,
+            for_secs: Some(20),
+        };
+        let start = Utc::now();
+        let at = |secs| start + Duration::seconds(secs);
+        let load = |one: Option<f64>| json!({ "load_avg": one.map(|one| json!({ "one": one })) });
+
+        // load_avg is only gathered every other harvest
+        let mut engine = AlertEngine::new(&[rule]);
+        assert!(engine.evaluate_value(&load(Some(5.0)), at(0)).is_empty());
+        assert!(engine.evaluate_value(&load(None), at(10)).is_empty());
+        let events = engine.evaluate_value(&load(Some(5.0)), at(20));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].status, AlertStatus::Firing);
+        assert!(engine.evaluate_value(&load(None), at(30)).is_empty());
+        let events = engine.evaluate_value(&load(Some(1.0)), at(40));
+        assert_eq!(events[0].status, AlertStatus::Resolved);
+
+        // Ratios are paired by element, not by position
+        let rule = AlertRule {
+            name: "disk_full".to_owned(),
+            metric: "disks.*.avail_space".to_owned(),
+            ratio_of: Some("disks.*.total_space".to_owned()),
+            op: "<".to_owned(),
+            threshold: json!(20),
+            recover: None,
+            for_secs: None,
+        };
+        let data = json!({"disks": [
+            {"mount_point": "/", "total_space": 100},
+            {"mount_point": "/home", "avail_space": 5, "total_space": 50}
+        ]});
+        let events = AlertEngine::new(&[rule]).evaluate_value(&data, at(0));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].instance, "disks./home.avail_space");
+        assert_eq!(events[0].value, json!(10.0));
+    }
+}
@@ -1,4 +1,4 @@
-use clap::{App, Arg, ArgMatches};
+use clap::{App, AppSettings, Arg, ArgMatches};
 
 /// Init the clap menu/args handling and return the ArgMatches instance.
 pub fn init_clap() -> ArgMatches {
@@ -20,5 +20,90 @@ pub fn init_clap() -> ArgMatches {
                 .about("Path to the config file")
                 .takes_value(true),
         )
+        .subcommand(
+            App::new("status")
+                .about("Print the status of the running agent")
+                .arg(
+                    Arg::new("socket")
+                        .short('s')
+                        .long("socket")
+                        .about("Path of the control socket (default /run/speculare.sock)")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            App::new("history")
+                .about("Print the local history of a metric from the running agent")
+                .arg(
+                    Arg::new("metric")
+                        .short('m')
+                        .long("metric")
+                        .about("Path of the metric (ex: memory.free), list them if missing")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("since")
+                        .short('s')
+                        .long("since")
+                        .about("How many minutes back (default 60)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("addr")
+                        .short('a')
+                        .long("addr")
+                        .about("Address of the history API (default history.listen of the config)")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            App::new("plugin-host")
+                .about("Run a plugin in a restricted process (used by plugins_sandbox)")
+                .setting(AppSettings::Hidden)
+                .arg(
+                    Arg::new("plugin")
+                        .long("plugin")
+                        .about("Path of the plugin")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("fd")
+                        .long("fd")
+                        .about("Inherited fd of the verified plugin")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("user")
+                        .long("user")
+                        .about("User to run the plugin as")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("max-memory")
+                        .long("max-memory")
+                        .about("Max address space (MB)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("max-cpu")
+                        .long("max-cpu")
+                        .about("Max CPU time (secs)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("max-files")
+                        .long("max-files")
+                        .about("Max number of open files")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::new("no-seccomp")
+                        .long("no-seccomp")
+                        .about("Don't install the seccomp filter")
+                        .takes_value(false),
+                ),
+        )
         .get_matches()
 }
@@ -0,0 +1,5 @@
+pub mod socket;
+pub use self::socket::*;
+
+pub mod status;
+pub use self::status::*;
@@ -0,0 +1,75 @@
+use super::status::{AgentStatus, SharedStatus};
+
+use std::{fs, os::unix::fs::PermissionsExt, path::Path};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{UnixListener, UnixStream},
+};
+
+/// Path of the control socket by default.
+pub const DEFAULT_CONTROL_SOCKET: &str = "/run/speculare.sock";
+
+/// Serve the status of the agent on the Unix socket at `path`.
+///
+/// Each connection receives the status as JSON and is then closed.
+pub async fn serve_control(status: SharedStatus, path: String) {
+    // Remove the socket left by a previous run, unless an agent is still serving it
+    if Path::new(&path).exists() {
+        if UnixStream::connect(&path).await.is_ok() {
+            error!("control socket: {} is used by another agent", path);
+            return;
+        }
+        if let Err(err) = fs::remove_file(&path) {
+            error!("control socket: cannot remove the stale {}: {}", path, err);
+            return;
+        }
+    }
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("control socket: cannot listen on {}: {}", path, err);
+            return;
+        }
+    };
+    // Only the owner (and root) can query the agent
+    if let Err(err) = fs::set_permissions(&path, fs::Permissions::from_mode(0o600)) {
+        warn!("control socket: cannot restrict {}: {}", path, err);
+    }
+    info!("control socket listening on {}", path);
+
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                error!("control socket: accept failed: {}", err);
+                continue;
+            }
+        };
+        let body = match serde_json::to_vec(&*status.lock().unwrap()) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("control socket: cannot serialize the status: {}", err);
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(err) = stream.write_all(&body).await {
+                debug!("control socket: cannot write the status: {}", err);
+            }
+            let _ = stream.shutdown().await;
+        });
+    }
+}
+
+/// Ask the agent listening on `path` for its status.
+pub async fn fetch_status(path: &str) -> Result<AgentStatus, Box<dyn std::error::Error>> {
+    let mut stream = UnixStream::connect(path).await.map_err(|err| {
+        format!(
+            "cannot connect to {} (is the agent running?): {}",
+            path, err
+        )
+    })?;
+    let mut body = Vec::new();
+    stream.read_to_end(&mut body).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
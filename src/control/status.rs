@@ -0,0 +1,115 @@
+use chrono::prelude::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+pub type SharedStatus = Arc<Mutex<AgentStatus>>;
+
+/// Outcome of the last sync attempt.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncResult {
+    pub at: DateTime<Utc>,
+    pub success: bool,
+    // None if no response was received
+    pub http_status: Option<u16>,
+    pub error: Option<String>,
+}
+
+/// What the running agent is doing, as reported over the control socket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AgentStatus {
+    pub version: String,
+    pub pid: u32,
+    pub config_path: String,
+    pub started_at: DateTime<Utc>,
+    pub plugins: Vec<String>,
+    pub last_harvest: Option<DateTime<Utc>>,
+    pub last_sync: Option<SyncResult>,
+    // Samples waiting to be sent
+    pub queue_depth: usize,
+    // Samples drained from the queue because the server couldn't be reached
+    pub dropped_samples: u64,
+}
+
+impl AgentStatus {
+    pub fn new(config_path: &str) -> Self {
+        AgentStatus {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            pid: std::process::id(),
+            config_path: config_path.to_owned(),
+            started_at: Utc::now(),
+            plugins: Vec::new(),
+            last_harvest: None,
+            last_sync: None,
+            queue_depth: 0,
+            dropped_samples: 0,
+        }
+    }
+
+    /// Human readable version of the status, as printed by the status command.
+    pub fn render(&self) -> String {
+        let never = || "never".to_owned();
+        let mut out = String::new();
+        let _ = writeln!(out, "version:         {} (pid {})", self.version, self.pid);
+        let _ = writeln!(out, "config:          {}", self.config_path);
+        let _ = writeln!(out, "started at:      {}", self.started_at.to_rfc3339());
+        let _ = writeln!(
+            out,
+            "plugins:         {}",
+            if self.plugins.is_empty() {
+                "none".to_owned()
+            } else {
+                self.plugins.join(", ")
+            }
+        );
+        let _ = writeln!(
+            out,
+            "last harvest:    {}",
+            self.last_harvest
+                .map_or_else(never, |date| date.to_rfc3339())
+        );
+        let _ = writeln!(
+            out,
+            "last sync:       {}",
+            self.last_sync.as_ref().map_or_else(never, |sync| {
+                let status = sync
+                    .http_status
+                    .map_or_else(|| "no response".to_owned(), |code| format!("HTTP {}", code));
+                match (&sync.error, sync.success) {
+                    (Some(err), _) => {
+                        format!("{} failed, {} ({})", sync.at.to_rfc3339(), status, err)
+                    }
+                    (None, true) => format!("{} ok, {}", sync.at.to_rfc3339(), status),
+                    (None, false) => format!("{} failed, {}", sync.at.to_rfc3339(), status),
+                }
+            })
+        );
+        let _ = writeln!(out, "queue depth:     {}", self.queue_depth);
+        let _ = write!(out, "dropped samples: {}", self.dropped_samples);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AgentStatus, SyncResult};
+
+    #[test]
+    fn render_status() {
+        let mut status = AgentStatus::new("/etc/speculare.config");
+        assert!(status.render().contains("last sync:       never"));
+
+        status.plugins = vec!["active_users".to_owned()];
+        status.last_sync = Some(SyncResult {
+            at: chrono::Utc::now(),
+            success: false,
+            http_status: Some(500),
+            error: None,
+        });
+        let render = status.render();
+        assert!(render.contains("plugins:         active_users"));
+        assert!(render.contains("failed, HTTP 500"));
+    }
+}
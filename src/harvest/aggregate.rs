@@ -0,0 +1,291 @@
+use super::data_harvest::{item_id, Data, ID_FIELDS};
+
+use chrono::prelude::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// Top level fields which are cumulative counters, p95 is meaningless for them.
+const COUNTERS: &[&str] = &[
+    "uptime",
+    "seq",
+    "cpu_stats",
+    "cpu_times",
+    "ioblocks",
+    "ionets",
+    "vmstat",
+];
+
+/// Top level fields which count what happened since the previous sample, they are
+/// summed over the window.
+const DELTAS: &[&str] = &["log_counters", "vmstat_delta"];
+
+/// Top level fields which are sent as events instead of being aggregated.
+const EVENTS: &[&str] = &["inventory", "kernel_events", "sockets", "clock_jump"];
+
+/// Top level fields which are the same for every sample.
+const IDENTITY: &[&str] = &[
+    "uuid",
+    "system",
+    "os_version",
+    "hostname",
+    "labels",
+    "created_at",
+];
+
+/// How the values of a metric evolve from one sample to the next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Kind {
+    // Current value (memory, disk space, ...)
+    Gauge,
+    // Cumulative since the boot (COUNTERS)
+    Counter,
+    // Since the previous sample (DELTAS)
+    Delta,
+}
+
+/// Statistics of a metric over the window.
+#[derive(Debug, Clone, Serialize)]
+pub struct Stats {
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+    pub last: f64,
+    // Only for gauges
+    pub p95: Option<f64>,
+    // Only for the per sample counters, total over the window
+    pub sum: Option<f64>,
+}
+
+/// Samples of one sync window collapsed into statistics per metric.
+#[derive(Debug, Clone, Serialize)]
+pub struct Aggregate {
+    pub uuid: String,
+    pub system: String,
+    pub os_version: String,
+    pub hostname: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub samples: i64,
+    // Numeric values, by path (ex: disks./home.avail_space)
+    pub metrics: BTreeMap<String, Stats>,
+    // Last value of the non numeric values (units state, plugins, ...)
+    pub values: BTreeMap<String, Value>,
+    // Samples' events (inventory, kernel events, ...) with the time they happened
+    pub events: Vec<Value>,
+}
+
+/// Flatten the leaves of `value` into `out`, arrays' elements are named by item_id.
+pub fn flatten(value: &Value, path: String, out: &mut Vec<(String, Value)>) {
+    let join = |id: &str| {
+        if path.is_empty() {
+            id.to_owned()
+        } else {
+            format!("{}.{}", path, id)
+        }
+    };
+
+    match value {
+        Value::Object(map) => {
+            for (key, child) in map {
+                flatten(child, join(key), out);
+            }
+        }
+        Value::Array(items) => {
+            for (idx, item) in items.iter().enumerate() {
+                flatten(item, join(&item_id(item, idx)), out);
+            }
+        }
+        Value::Null => {}
+        leaf => out.push((path, leaf.clone())),
+    }
+}
+
+/// Compute the stats of the values (in the samples' order).
+fn compute_stats(values: &[f64], kind: Kind) -> Stats {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    let sum = values.iter().sum::<f64>();
+    let p95 = if kind == Kind::Gauge {
+        let idx = ((sorted.len() as f64 * 0.95).ceil() as usize).max(1) - 1;
+        Some(sorted[idx])
+    } else {
+        None
+    };
+
+    Stats {
+        min: sorted[0],
+        max: sorted[sorted.len() - 1],
+        avg: sum / values.len() as f64,
+        last: values[values.len() - 1],
+        p95,
+        sum: if kind == Kind::Delta { Some(sum) } else { None },
+    }
+}
+
+/// Collapse the samples into an Aggregate, None if there is no sample.
+pub fn aggregate(samples: &[Data]) -> Option<Aggregate> {
+    let first = samples.first()?;
+    let last = samples.last()?;
+    let mut numbers: BTreeMap<String, Vec<f64>> = BTreeMap::new();
+    let mut values = BTreeMap::new();
+    let mut events = Vec::new();
+
+    for sample in samples {
+        let map = match serde_json::to_value(sample) {
+            Ok(Value::Object(map)) => map,
+            Ok(_) => continue,
+            Err(err) => {
+                error!("aggregate: cannot convert the Data: {}", err);
+                continue;
+            }
+        };
+
+        let mut event = Map::new();
+        let mut leaves = Vec::new();
+        for (key, value) in map {
+            if IDENTITY.contains(&key.as_str()) || value.is_null() {
+                continue;
+            }
+            if EVENTS.contains(&key.as_str()) {
+                event.insert(key, value);
+            } else {
+                flatten(&value, key, &mut leaves);
+            }
+        }
+        for (path, leaf) in leaves {
+            // Names of the elements are already part of the path
+            if ID_FIELDS.contains(&path.rsplit('.').next().unwrap_or_default()) {
+                continue;
+            }
+            match leaf.as_f64() {
+                Some(number) => numbers.entry(path).or_default().push(number),
+                None => {
+                    values.insert(path, leaf);
+                }
+            }
+        }
+        if !event.is_empty() {
+            event.insert(
+                "created_at".to_owned(),
+                Value::from(sample.created_at.to_rfc3339()),
+            );
+            events.push(Value::Object(event));
+        }
+    }
+
+    let metrics = numbers
+        .into_iter()
+        .map(|(path, samples)| {
+            let root = path.split('.').next().unwrap_or_default();
+            let kind = if COUNTERS.contains(&root) {
+                Kind::Counter
+            } else if DELTAS.contains(&root) {
+                Kind::Delta
+            } else {
+                Kind::Gauge
+            };
+            let stats = compute_stats(&samples, kind);
+            (path, stats)
+        })
+        .collect();
+
+    Some(Aggregate {
+        uuid: last.uuid.to_owned(),
+        system: last.system.to_owned(),
+        os_version: last.os_version.to_owned(),
+        hostname: last.hostname.to_owned(),
+        labels: last.labels.clone(),
+        from: first.created_at,
+        to: last.created_at,
+        samples: samples.len() as i64,
+        metrics,
+        values,
+        events,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{aggregate, compute_stats, flatten, Kind};
+    use crate::harvest::{data_harvest::Data, logtail::LogCounter, telemetry::AgentTelemetry};
+
+    use chrono::prelude::{TimeZone, Utc};
+    use serde_json::json;
+
+    #[test]
+    fn flatten_and_stats() {
+        let mut leaves = Vec::new();
+        flatten(
+            &json!({"memory": {"free": 10}, "disks": [{"mount_point": "/", "avail_space": 5}]}),
+            String::new(),
+            &mut leaves,
+        );
+        assert!(leaves.contains(&("memory.free".to_owned(), json!(10))));
+        assert!(leaves.contains(&("disks./.avail_space".to_owned(), json!(5))));
+
+        let values: Vec<f64> = (1..=20).map(f64::from).collect();
+        let stats = compute_stats(&values, Kind::Gauge);
+        assert_eq!(stats.min, 1.0);
+        assert_eq!(stats.max, 20.0);
+        assert_eq!(stats.avg, 10.5);
+        assert_eq!(stats.last, 20.0);
+        assert_eq!(stats.p95, Some(19.0));
+        assert_eq!(stats.sum, None);
+        assert_eq!(compute_stats(&values, Kind::Counter).p95, None);
+        assert_eq!(compute_stats(&values, Kind::Delta).sum, Some(210.0));
+    }
+
+    #[test]
+    fn aggregate_samples() {
+        assert!(aggregate(&[]).is_none());
+        let samples: Vec<Data> = [(2, 100), (0, 300), (5, 200)]
+            .iter()
+            .enumerate()
+            .map(|(idx, (matches, cache_depth))| {
+                let mut data = Data::with_host(
+                    "uuid".to_owned(),
+                    "Linux".to_owned(),
+                    "6.1".to_owned(),
+                    "host".to_owned(),
+                );
+                data.uptime = 1000 + idx as i64 * 60;
+                data.created_at = Utc.timestamp_opt(idx as i64 * 60, 0).unwrap();
+                data.log_counters = Some(vec![LogCounter {
+                    path: "/var/log/auth.log".to_owned(),
+                    pattern: "Failed password".to_owned(),
+                    count: *matches,
+                }]);
+                data.agent = Some(AgentTelemetry {
+                    cache_depth: *cache_depth,
+                    ..Default::default()
+                });
+                data
+            })
+            .collect();
+
+        let aggregate = aggregate(&samples).unwrap();
+        assert_eq!(aggregate.samples, 3);
+        assert_eq!(aggregate.from, samples[0].created_at);
+        assert_eq!(aggregate.to, samples[2].created_at);
+        // Matches of each interval
+        let matches = &aggregate.metrics["log_counters.0.count"];
+        assert_eq!(matches.sum, Some(7.0));
+        assert_eq!(matches.max, 5.0);
+        assert_eq!(matches.p95, None);
+        assert_eq!(
+            aggregate.values["log_counters.0.pattern"],
+            json!("Failed password")
+        );
+        // Cumulative
+        let uptime = &aggregate.metrics["uptime"];
+        assert_eq!((uptime.last, uptime.sum, uptime.p95), (1120.0, None, None));
+        // Gauge
+        let cache_depth = &aggregate.metrics["agent.cache_depth"];
+        assert_eq!(cache_depth.avg, 200.0);
+        assert_eq!(cache_depth.p95, Some(300.0));
+        assert_eq!(cache_depth.sum, None);
+    }
+}
@@ -0,0 +1,143 @@
+use chrono::prelude::{DateTime, Utc};
+use serde::Serialize;
+use std::io::{Error, ErrorKind};
+
+/// Synchronization state of the host clock.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ClockSync {
+    // Kernel state of the NTP discipline (adjtimex), None where it's unavailable
+    pub synchronized: Option<bool>,
+    pub offset_us: Option<i64>,
+    pub max_error_us: Option<i64>,
+    pub est_error_us: Option<i64>,
+    // Difference between our clock and the server's one (Date header) at the last sync
+    pub server_skew_ms: Option<i64>,
+    // True if the skew (NTP offset or server one) is above the configured threshold
+    pub skewed: bool,
+}
+
+/// Keep track of the server skew measured at each sync.
+pub struct ClockWatcher {
+    max_skew_ms: i64,
+    server_skew_ms: Option<i64>,
+}
+
+/// Kernel clock state as returned by adjtimex(2): (synchronized, offset, maxerror, esterror).
+#[cfg(target_os = "linux")]
+fn get_adjtimex() -> Result<(bool, i64, i64, i64), Error> {
+    let mut tx: libc::timex = unsafe { std::mem::zeroed() };
+    // modes = 0, only read the values
+    let state = unsafe { libc::adjtimex(&mut tx) };
+    if state == -1 {
+        return Err(Error::last_os_error());
+    }
+
+    // With STA_NANO the offset is expressed in ns instead of us
+    let offset = if tx.status & libc::STA_NANO != 0 {
+        tx.offset as i64 / 1000
+    } else {
+        tx.offset as i64
+    };
+    let synchronized = state != libc::TIME_ERROR && tx.status & libc::STA_UNSYNC == 0;
+
+    Ok((synchronized, offset, tx.maxerror as i64, tx.esterror as i64))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_adjtimex() -> Result<(bool, i64, i64, i64), Error> {
+    Err(Error::new(
+        ErrorKind::Other,
+        "adjtimex is only supported on Linux",
+    ))
+}
+
+/// Compute the skew (ms) between our clock and the server one from the Date header.
+///
+/// The server time is compared to the middle of the request to compensate the latency,
+/// the precision is still limited to the second by the header format.
+pub fn parse_server_skew(
+    date: &str,
+    sent_at: DateTime<Utc>,
+    received_at: DateTime<Utc>,
+) -> Result<i64, Error> {
+    let server = DateTime::parse_from_rfc2822(date)
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    let local = sent_at + (received_at - sent_at) / 2;
+
+    Ok((local - server.with_timezone(&Utc)).num_milliseconds())
+}
+
+impl ClockWatcher {
+    /// Create a watcher flagging skews above `max_skew_ms`.
+    ///
+    /// As the Date header is truncated to the second, it should be above 1000.
+    pub fn new(max_skew_ms: i64) -> Self {
+        ClockWatcher {
+            max_skew_ms,
+            server_skew_ms: None,
+        }
+    }
+
+    /// Record the Date header of a server response.
+    pub fn record_server_date(
+        &mut self,
+        date: &str,
+        sent_at: DateTime<Utc>,
+        received_at: DateTime<Utc>,
+    ) {
+        match parse_server_skew(date, sent_at, received_at) {
+            Ok(skew) => {
+                if skew.abs() > self.max_skew_ms {
+                    warn!("the clock is skewed by {}ms compared to the server", skew);
+                }
+                self.server_skew_ms = Some(skew);
+            }
+            Err(err) => error!("cannot parse the server Date header ({}): {}", date, err),
+        }
+    }
+
+    /// Get the clock synchronization state.
+    pub fn get_clock_sync(&self) -> ClockSync {
+        // The server skew is still reported without adjtimex (Linux only)
+        let kernel = match get_adjtimex() {
+            Ok(kernel) => Some(kernel),
+            Err(err) => {
+                if cfg!(target_os = "linux") {
+                    error!("[Eating] Clock fetching error: {}", err);
+                }
+                None
+            }
+        };
+        let offset_us = kernel.map(|(_, offset_us, _, _)| offset_us);
+        let skewed = matches!(offset_us, Some(offset) if offset.abs() / 1000 > self.max_skew_ms)
+            || matches!(self.server_skew_ms, Some(skew) if skew.abs() > self.max_skew_ms);
+
+        ClockSync {
+            synchronized: kernel.map(|(synchronized, _, _, _)| synchronized),
+            offset_us,
+            max_error_us: kernel.map(|(_, _, max_error_us, _)| max_error_us),
+            est_error_us: kernel.map(|(_, _, _, est_error_us)| est_error_us),
+            server_skew_ms: self.server_skew_ms,
+            skewed,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_server_skew;
+
+    use chrono::{
+        prelude::{TimeZone, Utc},
+        Duration,
+    };
+
+    #[test]
+    fn server_skew() {
+        let sent_at = Utc.with_ymd_and_hms(2021, 6, 25, 10, 0, 3).unwrap();
+        let received_at = sent_at + Duration::milliseconds(400);
+        let skew = parse_server_skew("Fri, 25 Jun 2021 10:00:00 GMT", sent_at, received_at);
+        assert_eq!(skew.unwrap(), 3200);
+        assert!(parse_server_skew("not a date", sent_at, received_at).is_err());
+    }
+}
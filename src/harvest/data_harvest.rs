@@ -1,15 +1,69 @@
-use crate::options::{Plugin, PluginsMap};
+use super::clock::{ClockSync, ClockWatcher};
+use super::inventory::{Inventory, InventoryWatcher};
+use super::kmsg::{KernelEvents, KmsgWatcher};
+use super::logtail::{LogCounter, LogTailer};
+use super::plugin_runner::PluginRunner;
+use super::sockets::{Sockets, SocketsWatcher};
+use super::systemd::{get_units_state, UnitState};
+use super::telemetry::{AgentTelemetry, Telemetry};
+use super::vmstat::{get_vmstat, VmStat};
+use crate::options::Plugin;
 
-use chrono::prelude::Utc;
+use chrono::prelude::{DateTime, Utc};
 use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
 use sys_metrics::{cpu::*, disks::*, host::*, memory::*, network::*};
 
+/// Difference (ms) between the wall clock and the monotonic clock elapsed time
+/// between two harvests above which we consider the wall clock jumped.
+const CLOCK_JUMP_THRESHOLD_MS: i64 = 1000;
+
+/// Monotonic clock still counting while suspended, unlike CLOCK_MONOTONIC on Linux
+/// (which Instant uses): a resume must not look like a clock jump.
+#[cfg(target_os = "linux")]
+const BOOT_CLOCK: libc::clockid_t = libc::CLOCK_BOOTTIME;
+#[cfg(not(target_os = "linux"))]
+const BOOT_CLOCK: libc::clockid_t = libc::CLOCK_MONOTONIC;
+
+/// Get the time (ms) of the BOOT_CLOCK.
+fn boot_clock_ms() -> i64 {
+    let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+    // Can't fail with a valid clock and pointer
+    unsafe { libc::clock_gettime(BOOT_CLOCK, &mut ts) };
+    ts.tv_sec as i64 * 1000 + ts.tv_nsec as i64 / 1_000_000
+}
+
+/// Get how much (ms) the wall clock jumped between two harvests, from the wall and
+/// monotonic time (ms) elapsed, None if below CLOCK_JUMP_THRESHOLD_MS.
+fn clock_jump(wall_ms: i64, mono_ms: i64) -> Option<i64> {
+    let jump = wall_ms - mono_ms;
+    if jump.abs() >= CLOCK_JUMP_THRESHOLD_MS {
+        Some(jump)
+    } else {
+        None
+    }
+}
+
+/// Fields used to name the elements of an array (instead of their index).
+pub const ID_FIELDS: &[&str] = &["key", "mount_point", "device_name", "interface", "name"];
+
+/// Name of an array element of a serialized Data, from its identifying field or its index.
+pub fn item_id(item: &Value, idx: usize) -> String {
+    ID_FIELDS
+        .iter()
+        .find_map(|field| item.get(field).and_then(Value::as_str))
+        .map_or_else(|| idx.to_string(), str::to_owned)
+}
+
 #[derive(Debug, Clone, Serialize)]
 pub struct Data {
     pub uuid: String,
     pub system: String,
     pub os_version: String,
     pub hostname: String,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
     pub uptime: i64,
     pub cpu_stats: Option<CpuStats>,
     pub cpu_times: Option<CpuTimes>,
@@ -19,29 +73,50 @@ pub struct Data {
     pub memory: Option<Memory>,
     pub swap: Option<Swap>,
     pub ionets: Option<Vec<IoNet>>,
-    pub created_at: chrono::NaiveDateTime,
+    pub vmstat: Option<VmStat>,
+    pub vmstat_delta: Option<VmStat>,
+    pub units: Option<Vec<UnitState>>,
+    pub kernel_events: Option<KernelEvents>,
+    pub log_counters: Option<Vec<LogCounter>>,
+    pub sockets: Option<Sockets>,
+    pub inventory: Option<Inventory>,
+    pub clock: Option<ClockSync>,
+    // Self-telemetry of the agent
+    pub agent: Option<AgentTelemetry>,
+    // Sequence number of the sample, monotonic for the lifetime of the agent
+    pub seq: i64,
+    // How much (ms) the wall clock jumped since the previous harvest (NTP step, ...)
+    pub clock_jump: Option<i64>,
+    pub created_at: DateTime<Utc>,
+    // BOOT_CLOCK (ms) and wall clock time of the previous harvest, to detect clock jumps
+    #[serde(skip)]
+    last_harvest: Option<(i64, DateTime<Utc>)>,
     pub plugins: Vec<Plugin>,
 }
 
-impl Default for Data {
-    fn default() -> Self {
+impl Data {
+    /// Create a new Data for the agent identified by `uuid`
+    pub fn new(uuid: String) -> Self {
         trace!("Init the default Data");
         let host_info = get_host_info()
             .unwrap_or_else(|err| panic!("Cannot get host_info of the host:{}", err));
 
-        // UUID can still be empty on some Linux platform (such as WSL)
-        let mut uuid =
-            get_uuid().unwrap_or_else(|err| panic!("Cannot get UUID of the host:{}", err));
-        // As a workaround for blank UUID, set the uuid to be the sha1 of hostname
-        if uuid.is_empty() {
-            uuid = sha1::Sha1::from(&host_info.hostname).digest().to_string();
-        }
+        Data::with_host(
+            uuid,
+            host_info.system,
+            host_info.os_version,
+            host_info.hostname,
+        )
+    }
 
+    /// Create a new Data, without any metric yet, for the host `hostname`
+    pub fn with_host(uuid: String, system: String, os_version: String, hostname: String) -> Self {
         Data {
             uuid,
-            system: host_info.system,
-            os_version: host_info.os_version,
-            hostname: host_info.hostname,
+            system,
+            os_version,
+            hostname,
+            labels: BTreeMap::new(),
             uptime: 0,
             cpu_stats: None,
             cpu_times: None,
@@ -51,26 +126,38 @@ impl Default for Data {
             memory: None,
             swap: None,
             ionets: None,
-            created_at: Utc::now().naive_local(),
+            vmstat: None,
+            vmstat_delta: None,
+            units: None,
+            kernel_events: None,
+            log_counters: None,
+            sockets: None,
+            inventory: None,
+            clock: None,
+            agent: None,
+            seq: 0,
+            clock_jump: None,
+            created_at: Utc::now(),
+            last_harvest: None,
             plugins: Vec::new(),
         }
     }
-}
 
-impl Data {
     /// Get each common metrics and "save" them in the Data struct
-    pub fn eat_data(&mut self, load_avg: bool) {
-        let eat_data_time = Utc::now().naive_local();
+    pub fn eat_data(&mut self, load_avg: bool, telemetry: &mut Telemetry) {
+        let eat_data_mono = boot_clock_ms();
+        let eat_data_time = Utc::now();
         trace!("eat_data: {:?}", eat_data_time);
 
         // Get the main host information (os, hostname, ...)
-        let host_info = get_host_info()
+        let host_info = telemetry
+            .time("host_info", get_host_info)
             .unwrap_or_else(|err| panic!("Cannot get host_info of the host:{}", err));
         // Assign self value to the value from host_info
         // Convert to i64, cause as of now the server doesn't handle u64
         self.uptime = host_info.uptime as i64;
         // Get the cpustats info (interrupts, ...)
-        self.cpu_stats = match get_cpustats() {
+        self.cpu_stats = match telemetry.time("cpu_stats", get_cpustats) {
             Ok(cpustats) => Some(cpustats),
             Err(err) => {
                 error!("[Eating] CpuStats fetching error: {}", err);
@@ -78,7 +165,7 @@ impl Data {
             }
         };
         // Get the cputimes info (user, idle, ...)
-        self.cpu_times = match get_cputimes() {
+        self.cpu_times = match telemetry.time("cpu_times", get_cputimes) {
             Ok(cputimes) => Some(cputimes),
             Err(err) => {
                 error!("[Eating] CpuTimes fetching error: {}", err);
@@ -93,7 +180,7 @@ impl Data {
             None
         };
         // Get the disks info (mount_path, used, ...) for physical disks
-        self.disks = match get_partitions_physical() {
+        self.disks = match telemetry.time("disks", get_partitions_physical) {
             Ok(partitions_phy) => Some(partitions_phy),
             Err(err) => {
                 error!("[Eating] Disks fetching error: {}", err);
@@ -101,7 +188,7 @@ impl Data {
             }
         };
         // Get the iostats (read/wrtn, ...) for physical disks
-        self.ioblocks = match get_physical_ioblocks() {
+        self.ioblocks = match telemetry.time("ioblocks", get_physical_ioblocks) {
             Ok(ioblocks) => Some(ioblocks),
             Err(err) => {
                 error!("[Eating] Ioblocks fetching error: {}", err);
@@ -109,7 +196,7 @@ impl Data {
             }
         };
         // Get the memory info (total, free, cached, ...)
-        self.memory = match get_memory() {
+        self.memory = match telemetry.time("memory", get_memory) {
             Ok(memory) => Some(memory),
             Err(err) => {
                 error!("[Eating] Memory fetching error: {}", err);
@@ -117,7 +204,7 @@ impl Data {
             }
         };
         // Get the swap info (total, free, ...)
-        self.swap = match get_swap() {
+        self.swap = match telemetry.time("swap", get_swap) {
             Ok(swap) => Some(swap),
             Err(err) => {
                 error!("[Eating] Swap fetching error: {}", err);
@@ -125,38 +212,97 @@ impl Data {
             }
         };
         // Get the network (physical) iocounters
-        self.ionets = match get_physical_ionets() {
+        self.ionets = match telemetry.time("ionets", get_physical_ionets) {
             Ok(ionets) => Some(ionets),
             Err(err) => {
                 error!("[Eating] Ionets fetching error: {}", err);
                 None
             }
         };
-        // Set the time at which this has been created
-        self.created_at = eat_data_time;
-    }
-
-    /// Get each plugins metrics and "save" them in the Data struct
-    pub fn eat_plugins(&mut self, plugins: &PluginsMap) {
-        trace!("eat_plugins: {:?}", Utc::now().naive_local());
-        // For each plugins, get their result and append to the Data
-        for (key, val) in plugins {
-            // Execute the entrypoint and get the return of it
-            let res = match (val.func)() {
-                Ok(res_func) => {
-                    debug!("PLUGIN {} returned: {:?}", key, res_func);
-                    res_func
+        // Get the memory pressure counters (major faults, swap in/out, oom kills, ...)
+        // and compute their increase since the previous harvest
+        if cfg!(target_os = "linux") {
+            match telemetry.time("vmstat", get_vmstat) {
+                Ok(vmstat) => {
+                    self.vmstat_delta = self.vmstat.as_ref().map(|prev| vmstat.delta(prev));
+                    self.vmstat = Some(vmstat);
                 }
                 Err(err) => {
-                    error!("PLUGIN {} failed with: {}", key, err);
-                    continue;
+                    error!("[Eating] Vmstat fetching error: {}", err);
+                    self.vmstat = None;
+                    self.vmstat_delta = None;
                 }
             };
-            // Add the plugin data to the Data struct
-            self.add_plugin(Plugin {
-                key: key.to_owned(),
-                val: res,
-            });
+        }
+        // Detect if the wall clock jumped (forward or backward) since the previous harvest
+        self.clock_jump = self.last_harvest.and_then(|(last_mono, last_time)| {
+            clock_jump(
+                (eat_data_time - last_time).num_milliseconds(),
+                eat_data_mono - last_mono,
+            )
+        });
+        if let Some(jump) = self.clock_jump {
+            warn!("the wall clock jumped by {}ms", jump);
+        }
+        self.last_harvest = Some((eat_data_mono, eat_data_time));
+        // Set the time at which this has been created, and its sequence number
+        self.created_at = eat_data_time;
+        self.seq += 1;
+    }
+
+    /// Get the state of the systemd units (or failed ones if empty) and "save" them in the Data struct
+    pub fn eat_units(&mut self, units: &[String]) {
+        self.units = match get_units_state(units) {
+            Ok(units) => Some(units),
+            Err(err) => {
+                error!("[Eating] Systemd units fetching error: {}", err);
+                None
+            }
+        };
+    }
+
+    /// Get the kernel events detected since the last harvest and "save" them in the Data struct
+    pub fn eat_kernel_events(&mut self, watcher: &KmsgWatcher) {
+        let events = watcher.drain();
+        // Only attach the events when there are some, to keep the payload small
+        self.kernel_events = if events.events.is_empty() && events.dropped == 0 {
+            None
+        } else {
+            Some(events)
+        };
+    }
+
+    /// Get the number of lines matching each log pattern since the last harvest
+    pub fn eat_logs(&mut self, tailer: &mut LogTailer) {
+        self.log_counters = Some(tailer.collect());
+    }
+
+    /// Get the listening sockets if `force` is true or if they changed since the last harvest
+    pub fn eat_sockets(&mut self, watcher: &mut SocketsWatcher, force: bool) {
+        self.sockets = match watcher.poll(force) {
+            Ok(sockets) => sockets,
+            Err(err) => {
+                error!("[Eating] Sockets fetching error: {}", err);
+                None
+            }
+        };
+    }
+
+    /// Get the host inventory if `check` is true and if it changed since the last time it was sent
+    pub fn eat_inventory(&mut self, watcher: &mut InventoryWatcher, check: bool) {
+        self.inventory = if check { watcher.poll() } else { None };
+    }
+
+    /// Get the clock synchronization state and "save" it in the Data struct
+    pub fn eat_clock(&mut self, watcher: &ClockWatcher) {
+        self.clock = Some(watcher.get_clock_sync());
+    }
+
+    /// Get the plugins results available for this harvest and "save" them in the Data struct
+    pub fn eat_plugins(&mut self, runner: &mut PluginRunner, telemetry: &mut Telemetry) {
+        trace!("eat_plugins: {:?}", Utc::now());
+        for plugin in runner.collect(telemetry) {
+            self.add_plugin(plugin);
         }
     }
 
@@ -170,3 +316,24 @@ impl Data {
         self.plugins.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{boot_clock_ms, clock_jump};
+
+    #[test]
+    fn detect_clock_jumps() {
+        assert_eq!(clock_jump(5000, 5000), None);
+        // Scheduling noise
+        assert_eq!(clock_jump(5999, 5000), None);
+        // NTP step forward and backward
+        assert_eq!(clock_jump(65_000, 5000), Some(60_000));
+        assert_eq!(clock_jump(-55_000, 5000), Some(-60_000));
+        // The monotonic clock includes the suspend, so a resume isn't a jump
+        assert_eq!(clock_jump(3_600_000, 3_600_000), None);
+
+        let before = boot_clock_ms();
+        assert!(before > 0);
+        assert!(boot_clock_ms() >= before);
+    }
+}
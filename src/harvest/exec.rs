@@ -0,0 +1,412 @@
+use crate::options::ExecPluginConfig;
+
+use serde_json::{json, Map, Value};
+use std::{
+    io::{self, Error, ErrorKind, Read},
+    process::{Command, Stdio},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Default timeout (secs) of a command.
+const DEFAULT_TIMEOUT: u64 = 10;
+
+/// Max size (bytes) of the output of a command.
+const MAX_OUTPUT: u64 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Influx,
+    Nagios,
+}
+
+impl Format {
+    fn parse(format: &str) -> Option<Format> {
+        match format {
+            "json" => Some(Format::Json),
+            "influx" => Some(Format::Influx),
+            "nagios" => Some(Format::Nagios),
+            _ => None,
+        }
+    }
+
+    /// Guess the format of the output of a command.
+    fn detect(output: &str) -> Format {
+        let first_line = output.trim_start().lines().next().unwrap_or_default();
+        if serde_json::from_str::<Value>(output).is_ok() {
+            Format::Json
+        } else if first_line.contains('|')
+            || ["OK", "WARNING", "CRITICAL", "UNKNOWN"]
+                .iter()
+                .any(|state| first_line.starts_with(state))
+        {
+            Format::Nagios
+        } else {
+            Format::Influx
+        }
+    }
+}
+
+/// Split `line` on `sep`, ignoring the escaped ones and (if `quotes`) the quoted ones.
+fn split_unescaped(line: &str, sep: char, quotes: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+    for (idx, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' if quotes => quoted = !quoted,
+            _ if c == sep && !quoted => {
+                parts.push(&line[start..idx]);
+                start = idx + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&line[start..]);
+    parts
+}
+
+/// Remove the backslashes escaping the following char.
+fn unescape(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => res.extend(chars.next()),
+            c => res.push(c),
+        }
+    }
+    res
+}
+
+/// Parse an Influx field value (1.5, 1i, 1u, "text", true, ...).
+fn parse_influx_value(value: &str) -> Option<Value> {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        return Some(Value::from(unescape(&value[1..value.len() - 1])));
+    }
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => return Some(Value::from(true)),
+        "f" | "F" | "false" | "False" | "FALSE" => return Some(Value::from(false)),
+        _ => {}
+    }
+    if let Some(int) = value.strip_suffix('i') {
+        return int.parse::<i64>().ok().map(Value::from);
+    }
+    if let Some(uint) = value.strip_suffix('u') {
+        return uint.parse::<u64>().ok().map(Value::from);
+    }
+    value.parse::<f64>().ok().map(Value::from)
+}
+
+/// Parse the Influx line protocol into a list of
+/// `{"measurement": ..., "tags": {...}, "fields": {...}, "timestamp": ...}`.
+fn parse_influx(output: &str) -> Result<Value, Error> {
+    let invalid =
+        |line: &str| Error::new(ErrorKind::InvalidData, format!("invalid line: {}", line));
+    let mut points = Vec::new();
+
+    for line in output.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let sections = split_unescaped(line, ' ', true);
+        let (key, fields) = match (sections.first(), sections.get(1)) {
+            (Some(key), Some(fields)) => (key, fields),
+            _ => return Err(invalid(line)),
+        };
+
+        let mut key = split_unescaped(key, ',', false).into_iter();
+        let measurement = unescape(key.next().unwrap_or_default());
+        let mut tags = Map::new();
+        for tag in key {
+            let (name, value) = tag.split_once('=').ok_or_else(|| invalid(line))?;
+            tags.insert(unescape(name), Value::from(unescape(value)));
+        }
+        let mut values = Map::new();
+        for field in split_unescaped(fields, ',', true) {
+            let (name, value) = field.split_once('=').ok_or_else(|| invalid(line))?;
+            values.insert(
+                unescape(name),
+                parse_influx_value(value).ok_or_else(|| invalid(line))?,
+            );
+        }
+
+        let mut point = json!({"measurement": measurement, "tags": tags, "fields": values});
+        if let Some(timestamp) = sections.get(2).and_then(|ts| ts.parse::<i64>().ok()) {
+            point["timestamp"] = Value::from(timestamp);
+        }
+        points.push(point);
+    }
+
+    Ok(Value::from(points))
+}
+
+/// Parse one Nagios perfdata (`'label'=value[uom];[warn];[crit];[min];[max]`) as a metric.
+fn parse_perfdata(perf: &str) -> Option<Value> {
+    let (label, rest) = perf.rsplit_once('=')?;
+    let mut parts = rest.split(';');
+    let value = parts.next()?;
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number.parse::<f64>().ok().map_or(Value::Null, Value::from);
+    let mut metric = json!({
+        "name": label.trim_matches('\''),
+        // c is the unit of the counters
+        "type": if unit == "c" { "counter" } else { "gauge" },
+        "value": number,
+    });
+    if !unit.is_empty() && unit != "c" {
+        metric["unit"] = Value::from(unit);
+    }
+    for (name, threshold) in ["warn", "crit", "min", "max"].iter().zip(parts) {
+        if !threshold.is_empty() {
+            metric[*name] = Value::from(threshold);
+        }
+    }
+    Some(metric)
+}
+
+/// Split the perfdata on whitespaces, the labels can be quoted and contain spaces.
+fn split_perfdata(perf: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    let mut quoted = false;
+    for (idx, c) in perf.char_indices() {
+        match c {
+            c if c.is_whitespace() && !quoted => {
+                if let Some(start) = start.take() {
+                    tokens.push(&perf[start..idx]);
+                }
+            }
+            c => {
+                if c == '\'' {
+                    quoted = !quoted;
+                }
+                start.get_or_insert(idx);
+            }
+        }
+    }
+    if let Some(start) = start {
+        tokens.push(&perf[start..]);
+    }
+    tokens
+}
+
+/// Parse a Nagios plugin output (`TEXT|perfdata` and the exit code as the state).
+fn parse_nagios(output: &str, code: Option<i32>) -> Value {
+    let status = match code {
+        Some(0) => "OK",
+        Some(1) => "WARNING",
+        Some(2) => "CRITICAL",
+        _ => "UNKNOWN",
+    };
+    let mut message = String::new();
+    let mut perfdata = Vec::new();
+    for (idx, line) in output.lines().enumerate() {
+        let (text, perf) = match line.split_once('|') {
+            Some((text, perf)) => (text, perf),
+            None => (line, ""),
+        };
+        if idx == 0 {
+            message = text.trim().to_owned();
+        }
+        perfdata.extend(split_perfdata(perf).into_iter().filter_map(parse_perfdata));
+    }
+
+    json!({
+        "status": status,
+        "code": code,
+        "message": message,
+        "perfdata": perfdata,
+    })
+}
+
+/// Run the command, killing it if it takes longer than `timeout`.
+fn run_command(
+    config: &ExecPluginConfig,
+    timeout: Duration,
+) -> Result<(Option<i32>, String), Error> {
+    let mut child = Command::new(&config.command)
+        .args(config.args.as_deref().unwrap_or_default())
+        .envs(config.env.iter().flatten())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+    let deadline = Instant::now() + timeout;
+
+    // Read the output in the background so the command never blocks on a full pipe
+    let mut stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| Error::new(ErrorKind::Other, "no stdout"))?;
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = Vec::new();
+        let res = (&mut stdout)
+            .take(MAX_OUTPUT)
+            .read_to_end(&mut output)
+            // Drain the rest, the command would block on a full pipe otherwise
+            .and_then(|_| io::copy(&mut stdout, &mut io::sink()))
+            .and_then(|rest| {
+                if rest > 0 {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("output larger than {} bytes", MAX_OUTPUT),
+                    ));
+                }
+                String::from_utf8(output).map_err(|err| Error::new(ErrorKind::InvalidData, err))
+            });
+        let _ = tx.send(res);
+    });
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(Error::new(ErrorKind::TimedOut, "timed out"));
+        }
+        thread::sleep(Duration::from_millis(10));
+    };
+    // Something spawned by the command might still hold the pipe
+    let output = rx
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        .map_err(|_| Error::new(ErrorKind::TimedOut, "timed out reading the output"))??;
+
+    Ok((status.code(), output))
+}
+
+/// Run the exec plugin and parse its output.
+pub fn run_exec_plugin(config: &ExecPluginConfig) -> Result<Value, Error> {
+    let timeout = Duration::from_secs(config.timeout.unwrap_or(DEFAULT_TIMEOUT));
+    let (code, output) = run_command(config, timeout)?;
+    let format = match &config.format {
+        Some(format) => Format::parse(format).ok_or_else(|| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("unknown format {}", format),
+            )
+        })?,
+        // A Nagios check without perfdata (DISK OK - ...) looks like Influx
+        None => match Format::detect(&output) {
+            Format::Influx if parse_influx(&output).is_err() => Format::Nagios,
+            format => format,
+        },
+    };
+
+    // For Nagios the exit code is the state of the check, not a failure
+    if format != Format::Nagios && code != Some(0) {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!("exited with {:?}", code),
+        ));
+    }
+    match format {
+        Format::Json => serde_json::from_str(&output).map_err(Error::from),
+        Format::Influx => parse_influx(&output),
+        Format::Nagios => Ok(parse_nagios(&output, code)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_influx, parse_nagios, run_exec_plugin, Format};
+    use crate::options::ExecPluginConfig;
+
+    use serde_json::json;
+
+    #[test]
+    fn parse_outputs() {
+        let influx = parse_influx(
+            "# comment\nweather,location=us\\ midwest temperature=82,humidity=71i,sky=\"clear, sunny\" 1465839830100400200\n",
+        )
+        .unwrap();
+        assert_eq!(
+            influx,
+            json!([{
+                "measurement": "weather",
+                "tags": {"location": "us midwest"},
+                "fields": {"temperature": 82.0, "humidity": 71, "sky": "clear, sunny"},
+                "timestamp": 1465839830100400200i64
+            }])
+        );
+        assert!(parse_influx("weather").is_err());
+
+        let nagios = parse_nagios(
+            "DISK WARNING - free space: / 3326 MB (5%) | '/ free'=3326MB;1000;500;0;71000 inodes=80%;;\n",
+            Some(1),
+        );
+        assert_eq!(nagios["status"], "WARNING");
+        assert_eq!(
+            nagios["message"],
+            "DISK WARNING - free space: / 3326 MB (5%)"
+        );
+        assert_eq!(
+            nagios["perfdata"],
+            json!([
+                {"name": "/ free", "type": "gauge", "value": 3326.0, "unit": "MB", "warn": "1000", "crit": "500", "min": "0", "max": "71000"},
+                {"name": "inodes", "type": "gauge", "value": 80.0, "unit": "%"}
+            ])
+        );
+
+        assert_eq!(Format::detect("{\"users\": 3}"), Format::Json);
+        assert_eq!(Format::detect("OK - all good"), Format::Nagios);
+        assert_eq!(Format::detect("cpu usage=3"), Format::Influx);
+    }
+
+    #[test]
+    fn run_commands() {
+        let mut config = ExecPluginConfig {
+            name: "up".to_owned(),
+            command: "sh".to_owned(),
+            args: Some(vec![
+                "-c".to_owned(),
+                "echo \"{\\\"up\\\": $UP}\"".to_owned(),
+            ]),
+            env: Some(
+                vec![("UP".to_owned(), "1".to_owned())]
+                    .into_iter()
+                    .collect(),
+            ),
+            interval: None,
+            timeout: Some(1),
+            format: None,
+        };
+        assert_eq!(run_exec_plugin(&config).unwrap(), json!({"up": 1}));
+
+        // Nagios without perfdata
+        config.args = Some(vec![
+            "-c".to_owned(),
+            "echo 'DISK CRITICAL - free space: / 10 MB (1%)'; exit 2".to_owned(),
+        ]);
+        let nagios = run_exec_plugin(&config).unwrap();
+        assert_eq!(nagios["status"], "CRITICAL");
+        assert_eq!(
+            nagios["message"],
+            "DISK CRITICAL - free space: / 10 MB (1%)"
+        );
+
+        config.args = Some(vec!["-c".to_owned(), "sleep 5".to_owned()]);
+        assert!(run_exec_plugin(&config).is_err());
+
+        // The output is capped
+        config.args = Some(vec![
+            "-c".to_owned(),
+            "head -c 2000000 /dev/zero".to_owned(),
+        ]);
+        assert_eq!(
+            run_exec_plugin(&config).unwrap_err().to_string(),
+            "output larger than 1048576 bytes"
+        );
+    }
+}
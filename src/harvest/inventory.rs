@@ -0,0 +1,303 @@
+use serde::Serialize;
+use std::{collections::BTreeMap, ffi::CStr, fs, path::Path};
+use sys_metrics::host::get_host_info;
+
+/// Static description of the host, only sent when it changes.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Inventory {
+    pub agent_version: String,
+    pub system: String,
+    pub os_version: String,
+    pub hostname: String,
+    pub kernel_version: String,
+    pub cpu_model: String,
+    pub cpu_cores: i64,
+    pub cpu_flags: Vec<String>,
+    pub memory_total: i64,
+    pub nics: Vec<Nic>,
+    pub disks: Vec<DiskInfo>,
+    pub virtualization: Option<String>,
+    pub boot_time: i64,
+}
+
+/// Network interface and its addresses.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Nic {
+    pub name: String,
+    pub mac: Option<String>,
+    pub addresses: Vec<String>,
+}
+
+/// Block device (whole disk, not partitions).
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct DiskInfo {
+    pub name: String,
+    pub size: i64,
+    pub model: Option<String>,
+}
+
+/// Keep track of the last sent Inventory to only send it again on change.
+#[derive(Default)]
+pub struct InventoryWatcher {
+    last: Option<Inventory>,
+}
+
+/// Read a file and trim it, None if it doesn't exist or is empty.
+fn read_trimmed<P: AsRef<Path>>(path: P) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|content| content.trim().to_owned())
+        .filter(|content| !content.is_empty())
+}
+
+/// Get the kernel release using uname(2).
+fn get_kernel_version() -> String {
+    let mut uts: libc::utsname = unsafe { std::mem::zeroed() };
+    // Safe as uts is a valid utsname which uname will fill
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr(uts.release.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Parse /proc/cpuinfo and return the (model, number of logical cores, flags).
+fn parse_cpuinfo(content: &str) -> (String, i64, Vec<String>) {
+    let mut model = String::new();
+    let mut cores = 0;
+    let mut flags = Vec::new();
+
+    for line in content.lines() {
+        let (key, val) = match line.find(':') {
+            Some(idx) => (line[..idx].trim(), line[idx + 1..].trim()),
+            None => continue,
+        };
+        match key {
+            "processor" => cores += 1,
+            "model name" if model.is_empty() => model = val.to_owned(),
+            "flags" if flags.is_empty() => {
+                flags = val.split_whitespace().map(str::to_owned).collect()
+            }
+            _ => {}
+        }
+    }
+
+    (model, cores, flags)
+}
+
+/// Get the value (in bytes) of MemTotal from /proc/meminfo.
+fn get_memory_total() -> i64 {
+    fs::read_to_string("/proc/meminfo")
+        .unwrap_or_default()
+        .lines()
+        .find(|line| line.starts_with("MemTotal:"))
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|kb| kb.parse::<i64>().ok())
+        .map_or(0, |kb| kb * 1024)
+}
+
+/// Get the boot time (epoch secs) from the btime line of /proc/stat.
+fn get_boot_time() -> i64 {
+    fs::read_to_string("/proc/stat")
+        .unwrap_or_default()
+        .lines()
+        .find(|line| line.starts_with("btime "))
+        .and_then(|line| line[6..].trim().parse::<i64>().ok())
+        .unwrap_or(0)
+}
+
+/// Get the interfaces with their MAC (from sysfs) and addresses (from getifaddrs).
+fn get_nics() -> Vec<Nic> {
+    let mut nics: BTreeMap<String, Nic> = BTreeMap::new();
+
+    let mut ifap: *mut libc::ifaddrs = std::ptr::null_mut();
+    // Safe as getifaddrs allocate the list which is freed below
+    if unsafe { libc::getifaddrs(&mut ifap) } == 0 {
+        let mut cur = ifap;
+        while !cur.is_null() {
+            let ifa = unsafe { &*cur };
+            cur = ifa.ifa_next;
+            let name = unsafe { CStr::from_ptr(ifa.ifa_name) }
+                .to_string_lossy()
+                .into_owned();
+            let nic = nics.entry(name.clone()).or_insert_with(|| Nic {
+                name,
+                ..Default::default()
+            });
+            if ifa.ifa_addr.is_null() {
+                continue;
+            }
+            match i32::from(unsafe { (*ifa.ifa_addr).sa_family }) {
+                libc::AF_INET => {
+                    let addr = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                    nic.addresses.push(
+                        std::net::Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).to_string(),
+                    );
+                }
+                libc::AF_INET6 => {
+                    let addr = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                    nic.addresses
+                        .push(std::net::Ipv6Addr::from(addr.sin6_addr.s6_addr).to_string());
+                }
+                _ => {}
+            }
+        }
+        unsafe { libc::freeifaddrs(ifap) };
+    }
+
+    for nic in nics.values_mut() {
+        nic.mac = read_trimmed(format!("/sys/class/net/{}/address", nic.name));
+    }
+    nics.into_values().collect()
+}
+
+/// Get the block devices from /sys/block, ignoring the virtual ones.
+fn get_disks() -> Vec<DiskInfo> {
+    let entries = match fs::read_dir("/sys/block") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut disks: Vec<DiskInfo> = entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if ["loop", "ram", "zram", "dm-"]
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+            {
+                return None;
+            }
+            // The size is always expressed in 512 bytes sectors
+            let sectors = read_trimmed(entry.path().join("size"))
+                .and_then(|size| size.parse::<i64>().ok())
+                .unwrap_or(0);
+            Some(DiskInfo {
+                size: sectors * 512,
+                model: read_trimmed(entry.path().join("device/model")),
+                name,
+            })
+        })
+        .collect();
+    disks.sort_by(|a, b| a.name.cmp(&b.name));
+    disks
+}
+
+/// Detect if we're running in a container or a VM, and which one.
+fn get_virtualization(cpu_flags: &[String]) -> Option<String> {
+    // Containers first, as they also inherit the VM info of their host
+    if Path::new("/.dockerenv").exists() {
+        return Some("docker".to_owned());
+    }
+    if Path::new("/run/.containerenv").exists() {
+        return Some("podman".to_owned());
+    }
+    let cgroup = fs::read_to_string("/proc/1/cgroup").unwrap_or_default();
+    for container in &["kubepods", "docker", "lxc"] {
+        if cgroup.contains(container) {
+            return Some((*container).to_owned());
+        }
+    }
+    if fs::read_to_string("/proc/sys/kernel/osrelease")
+        .unwrap_or_default()
+        .to_lowercase()
+        .contains("microsoft")
+    {
+        return Some("wsl".to_owned());
+    }
+
+    let dmi = format!(
+        "{} {}",
+        read_trimmed("/sys/class/dmi/id/sys_vendor").unwrap_or_default(),
+        read_trimmed("/sys/class/dmi/id/product_name").unwrap_or_default()
+    )
+    .to_lowercase();
+    for (pattern, name) in &[
+        ("kvm", "kvm"),
+        ("qemu", "qemu"),
+        ("vmware", "vmware"),
+        ("virtualbox", "virtualbox"),
+        ("xen", "xen"),
+        ("microsoft", "hyperv"),
+        ("amazon ec2", "aws"),
+        ("google", "gce"),
+    ] {
+        if dmi.contains(pattern) {
+            return Some((*name).to_owned());
+        }
+    }
+    // Hypervisor that we don't know about
+    if cpu_flags.iter().any(|flag| flag == "hypervisor") {
+        return Some("unknown".to_owned());
+    }
+
+    None
+}
+
+impl Inventory {
+    /// Gather the inventory of the host, each part is best effort.
+    pub fn gather() -> Self {
+        let (cpu_model, cpu_cores, cpu_flags) =
+            parse_cpuinfo(&fs::read_to_string("/proc/cpuinfo").unwrap_or_default());
+        let mut inventory = Inventory {
+            agent_version: env!("CARGO_PKG_VERSION").to_owned(),
+            kernel_version: get_kernel_version(),
+            virtualization: get_virtualization(&cpu_flags),
+            cpu_model,
+            cpu_cores,
+            cpu_flags,
+            memory_total: get_memory_total(),
+            nics: get_nics(),
+            disks: get_disks(),
+            boot_time: get_boot_time(),
+            ..Default::default()
+        };
+        match get_host_info() {
+            Ok(host_info) => {
+                inventory.system = host_info.system;
+                inventory.os_version = host_info.os_version;
+                inventory.hostname = host_info.hostname;
+            }
+            Err(err) => error!("[Inventory] host_info fetching error: {}", err),
+        }
+
+        inventory
+    }
+}
+
+impl InventoryWatcher {
+    /// Gather the inventory and return it if it's the first call or if it changed.
+    pub fn poll(&mut self) -> Option<Inventory> {
+        let inventory = Inventory::gather();
+        if self.last.as_ref() == Some(&inventory) {
+            return None;
+        }
+        info!("host inventory changed, it will be sent");
+        self.last = Some(inventory.clone());
+        Some(inventory)
+    }
+
+    /// Forget the last sent inventory so that the next poll returns it.
+    ///
+    /// Used when the data_cache has been drained and the inventory might have been lost.
+    pub fn reset(&mut self) {
+        self.last = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_cpuinfo;
+
+    #[test]
+    fn parse_cpu() {
+        let (model, cores, flags) = parse_cpuinfo(
+            "processor\t: 0\nmodel name\t: Intel(R) Xeon(R)\nflags\t\t: fpu vme hypervisor\n\n\
+             processor\t: 1\nmodel name\t: Intel(R) Xeon(R)\nflags\t\t: fpu vme hypervisor\n",
+        );
+        assert_eq!(model, "Intel(R) Xeon(R)");
+        assert_eq!(cores, 2);
+        assert_eq!(flags, vec!["fpu", "vme", "hypervisor"]);
+    }
+}
@@ -0,0 +1,204 @@
+use chrono::prelude::Utc;
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Error, ErrorKind, Seek, SeekFrom},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+/// Patterns (substring) used to classify a kernel log line.
+const PATTERNS: &[(&str, &str)] = &[
+    // Logged once per kill, unlike "Out of memory: Kill process" or "oom-kill:"
+    // which precede it depending on the kernel version
+    ("oom_kill", "Killed process"),
+    ("mce", "Machine check"),
+    ("mce", "mce:"),
+    ("mce", "[Hardware Error]"),
+    ("edac", "EDAC "),
+    ("fs_error", "EXT4-fs error"),
+    ("fs_error", "Corruption detected"),
+    ("fs_error", "Corruption of in-memory data"),
+    ("fs_error", "Filesystem has been shut down"),
+    ("fs_error", "BTRFS error"),
+    ("fs_error", "I/O error"),
+    ("segfault", "segfault at"),
+];
+
+/// A kernel event detected in the log source.
+///
+/// Identical events (ignoring numbers such as pid or addresses) are merged
+/// and `count` is incremented instead.
+#[derive(Debug, Clone, Serialize)]
+pub struct KernelEvent {
+    pub kind: String,
+    pub message: String,
+    pub count: i64,
+    pub first_seen: chrono::DateTime<Utc>,
+    pub last_seen: chrono::DateTime<Utc>,
+}
+
+/// Events gathered since the last harvest.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct KernelEvents {
+    pub events: Vec<KernelEvent>,
+    // Number of events discarded due to the rate limiting
+    pub dropped: i64,
+}
+
+#[derive(Debug, Default)]
+struct EventQueue {
+    events: Vec<(String, KernelEvent)>,
+    dropped: i64,
+}
+
+/// Watch a kernel log source (/dev/kmsg or a regular log file) in a background thread.
+pub struct KmsgWatcher {
+    queue: Arc<Mutex<EventQueue>>,
+}
+
+/// Return the kind of event the line represent, if any.
+pub fn classify(line: &str) -> Option<&'static str> {
+    PATTERNS
+        .iter()
+        .find(|(_, pattern)| line.contains(pattern))
+        .map(|(kind, _)| *kind)
+}
+
+/// Strip the /dev/kmsg record header ("pri,seq,ts,flags;") if present.
+fn strip_header(line: &str) -> &str {
+    match line.find(';') {
+        Some(idx) if line[..idx].split(',').count() >= 3 => &line[idx + 1..],
+        _ => line,
+    }
+}
+
+/// Key used to merge identical events, digits are ignored.
+fn dedup_key(kind: &str, message: &str) -> String {
+    let mut key = String::with_capacity(kind.len() + message.len() + 1);
+    key.push_str(kind);
+    key.push(':');
+    key.extend(message.chars().filter(|c| !c.is_ascii_digit()));
+    key
+}
+
+impl EventQueue {
+    fn push(&mut self, kind: &str, message: &str, max_events: usize) {
+        let now = Utc::now();
+        let key = dedup_key(kind, message);
+
+        if let Some((_, event)) = self.events.iter_mut().find(|(k, _)| *k == key) {
+            event.count += 1;
+            event.last_seen = now;
+            return;
+        }
+        // Don't let a flood of distinct events blow up the payload
+        if self.events.len() >= max_events {
+            self.dropped += 1;
+            return;
+        }
+        self.events.push((
+            key,
+            KernelEvent {
+                kind: kind.to_owned(),
+                message: message.to_owned(),
+                count: 1,
+                first_seen: now,
+                last_seen: now,
+            },
+        ));
+    }
+}
+
+impl KmsgWatcher {
+    /// Open the log source and start following it from its end.
+    ///
+    /// At most `max_events` distinct events are kept between two harvests.
+    pub fn start(path: &str, max_events: usize) -> Result<Self, Error> {
+        let mut file = File::open(path)?;
+        // Skip what's already in the ring buffer/file, we only want new events
+        file.seek(SeekFrom::End(0))?;
+
+        let queue = Arc::new(Mutex::new(EventQueue::default()));
+        let thread_queue = queue.clone();
+        let path = path.to_owned();
+        thread::Builder::new()
+            .name("kmsg".to_owned())
+            .spawn(move || {
+                let mut reader = BufReader::new(file);
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    match reader.read_line(&mut line) {
+                        // EOF of a regular file, wait for more lines
+                        Ok(0) => thread::sleep(Duration::from_millis(500)),
+                        Ok(_) => {
+                            let message = strip_header(line.trim_end());
+                            if let Some(kind) = classify(message) {
+                                debug!("kmsg: {} event: {}", kind, message);
+                                thread_queue.lock().unwrap().push(kind, message, max_events);
+                            }
+                        }
+                        // Records overwritten in /dev/kmsg before we read them
+                        Err(err) if err.kind() == ErrorKind::BrokenPipe => {
+                            warn!("kmsg: some records of {} were lost", path);
+                        }
+                        Err(err) => {
+                            error!("kmsg: cannot read {}: {}", path, err);
+                            thread::sleep(Duration::from_secs(1));
+                        }
+                    }
+                }
+            })?;
+
+        Ok(KmsgWatcher { queue })
+    }
+
+    /// Take the events gathered since the previous call.
+    pub fn drain(&self) -> KernelEvents {
+        let mut queue = self.queue.lock().unwrap();
+        let dropped = queue.dropped;
+        queue.dropped = 0;
+
+        KernelEvents {
+            events: queue.events.drain(..).map(|(_, event)| event).collect(),
+            dropped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{classify, strip_header, EventQueue};
+
+    #[test]
+    fn classify_and_dedup() {
+        let line = strip_header("3,1234,5678,-;foo[42]: segfault at 0 ip 0000 sp 0000 error 4");
+        assert_eq!(classify(line), Some("segfault"));
+        assert_eq!(classify("usb 1-1: new high-speed USB device"), None);
+        assert_eq!(
+            classify("Out of memory: Killed process 1234 (java)"),
+            Some("oom_kill")
+        );
+        assert_eq!(classify("Out of memory: Kill process 1234 (java)"), None);
+        assert_eq!(classify("XFS (sda1): Mounting V5 Filesystem"), None);
+        assert_eq!(
+            classify("XFS (sda1): Corruption detected. Unmount and run xfs_repair"),
+            Some("fs_error")
+        );
+        assert_eq!(
+            classify("XFS (sda1): metadata I/O error in \"xfs_trans_read_buf_map\""),
+            Some("fs_error")
+        );
+
+        let mut queue = EventQueue::default();
+        queue.push("segfault", "foo[42]: segfault at 0", 2);
+        queue.push("segfault", "foo[43]: segfault at 0", 2);
+        queue.push("fs_error", "EXT4-fs error (device sda1)", 2);
+        queue.push("mce", "mce: CPU0 Machine check", 2);
+        assert_eq!(queue.events.len(), 2);
+        assert_eq!(queue.events[0].1.count, 2);
+        assert_eq!(queue.dropped, 1);
+    }
+}
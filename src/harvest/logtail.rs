@@ -0,0 +1,303 @@
+use crate::options::LogConfig;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{Error, Read, Seek, SeekFrom},
+    os::unix::fs::MetadataExt,
+    path::Path,
+};
+
+/// Max bytes read from a file per harvest, the rest is read on the next ones.
+const MAX_READ_PER_TICK: u64 = 4 * 1024 * 1024;
+
+/// Number of lines matching a pattern of a log file during the last interval.
+#[derive(Debug, Clone, Serialize)]
+pub struct LogCounter {
+    pub path: String,
+    pub pattern: String,
+    pub count: i64,
+}
+
+/// Read position of a file, persisted across restarts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct FileState {
+    inode: u64,
+    offset: u64,
+}
+
+struct TailedFile {
+    path: String,
+    patterns: Vec<(String, Regex)>,
+    file: Option<File>,
+    state: FileState,
+}
+
+/// Follow log files across rotation and count the lines matching their patterns.
+pub struct LogTailer {
+    files: Vec<TailedFile>,
+    state_path: String,
+}
+
+impl TailedFile {
+    /// Read the complete lines added since the last call (at most MAX_READ_PER_TICK
+    /// bytes) and count the matches.
+    ///
+    /// Return true if the end of the file has been reached.
+    fn read_new_lines(&mut self, counts: &mut [i64]) -> Result<bool, Error> {
+        let file = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Ok(true),
+        };
+        let len = file.metadata()?.len();
+        // The file has been truncated (copytruncate), restart from the beginning
+        if len < self.state.offset {
+            debug!("logtail: {} has been truncated", self.path);
+            self.state.offset = 0;
+        }
+        file.seek(SeekFrom::Start(self.state.offset))?;
+        let mut buf = Vec::new();
+        file.take(MAX_READ_PER_TICK).read_to_end(&mut buf)?;
+        let caught_up = self.state.offset + buf.len() as u64 >= len;
+        // Only consume complete lines, the rest will be read on the next call
+        let end = match buf.iter().rposition(|b| *b == b'\n') {
+            Some(idx) => idx + 1,
+            // A line longer than the limit, skip it rather than being stuck on it
+            None if buf.len() as u64 == MAX_READ_PER_TICK => {
+                warn!("logtail: skipping a too long line of {}", self.path);
+                self.state.offset += buf.len() as u64;
+                return Ok(false);
+            }
+            None => return Ok(caught_up),
+        };
+        for line in String::from_utf8_lossy(&buf[..end]).lines() {
+            for (idx, (_, regex)) in self.patterns.iter().enumerate() {
+                if regex.is_match(line) {
+                    counts[idx] += 1;
+                }
+            }
+        }
+        self.state.offset += end as u64;
+        Ok(caught_up)
+    }
+
+    /// Open the file at `path` if it's not already or if it has been rotated.
+    ///
+    /// Return true if the file has been (re)opened.
+    fn reopen(&mut self) -> Result<bool, Error> {
+        let inode = fs::metadata(&self.path)?.ino();
+        if self.file.is_some() && inode == self.state.inode {
+            return Ok(false);
+        }
+        let mut file = File::open(&self.path)?;
+        // Not the file we were reading before the rotation (or the restart), start from its beginning
+        if inode != self.state.inode {
+            self.state = FileState { inode, offset: 0 };
+        }
+        file.seek(SeekFrom::Start(self.state.offset))?;
+        self.file = Some(file);
+        Ok(true)
+    }
+
+    fn collect(&mut self, counters: &mut Vec<LogCounter>) {
+        let mut counts = vec![0; self.patterns.len()];
+        // Finish reading the current file, even if it has been rotated meanwhile
+        match self.read_new_lines(&mut counts) {
+            Ok(true) => {}
+            // Keep following the current file until it's fully read
+            Ok(false) => return self.push_counters(counts, counters),
+            Err(err) => error!("logtail: cannot read {}: {}", self.path, err),
+        }
+        match self.reopen() {
+            Ok(true) => {
+                debug!("logtail: (re)opened {}", self.path);
+                if let Err(err) = self.read_new_lines(&mut counts) {
+                    error!("logtail: cannot read {}: {}", self.path, err);
+                }
+            }
+            Ok(false) => {}
+            Err(err) => {
+                // Rotated but not yet recreated, or simply missing
+                trace!("logtail: cannot open {}: {}", self.path, err);
+                self.file = None;
+            }
+        }
+
+        self.push_counters(counts, counters);
+    }
+
+    fn push_counters(&self, counts: Vec<i64>, counters: &mut Vec<LogCounter>) {
+        counters.extend(
+            self.patterns
+                .iter()
+                .zip(counts)
+                .map(|((name, _), count)| LogCounter {
+                    path: self.path.clone(),
+                    pattern: name.clone(),
+                    count,
+                }),
+        );
+    }
+}
+
+impl LogTailer {
+    /// Compile the patterns and restore the read offsets saved at `state_path`.
+    ///
+    /// Files without saved offset are followed from their end.
+    pub fn new(logs: &[LogConfig], state_path: &str) -> Self {
+        let saved: HashMap<String, FileState> = fs::read_to_string(state_path)
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default();
+
+        let files = logs
+            .iter()
+            .map(|log| {
+                let patterns = log
+                    .patterns
+                    .iter()
+                    .filter_map(|(name, pattern)| match Regex::new(pattern) {
+                        Ok(regex) => Some((name.to_owned(), regex)),
+                        Err(err) => {
+                            error!(
+                                "logtail: invalid pattern {} for {}: {}",
+                                name, log.path, err
+                            );
+                            None
+                        }
+                    })
+                    .collect();
+                let state = match (saved.get(&log.path), fs::metadata(&log.path)) {
+                    (Some(state), _) => state.clone(),
+                    (None, Ok(meta)) => FileState {
+                        inode: meta.ino(),
+                        offset: meta.len(),
+                    },
+                    (None, Err(_)) => FileState::default(),
+                };
+
+                TailedFile {
+                    path: log.path.to_owned(),
+                    patterns,
+                    file: None,
+                    state,
+                }
+            })
+            .collect();
+
+        LogTailer {
+            files,
+            state_path: state_path.to_owned(),
+        }
+    }
+
+    /// Count the matches of each pattern since the last call and persist the offsets.
+    pub fn collect(&mut self) -> Vec<LogCounter> {
+        let mut counters = Vec::new();
+        for file in &mut self.files {
+            file.collect(&mut counters);
+        }
+        if let Err(err) = self.save() {
+            error!(
+                "logtail: cannot save the offsets to {}: {}",
+                self.state_path, err
+            );
+        }
+        counters
+    }
+
+    /// Write the offsets to a temporary file and rename it to avoid partial writes.
+    fn save(&self) -> Result<(), Error> {
+        let state: HashMap<&str, &FileState> = self
+            .files
+            .iter()
+            .map(|file| (file.path.as_str(), &file.state))
+            .collect();
+        if let Some(folder) = Path::new(&self.state_path).parent() {
+            fs::create_dir_all(folder)?;
+        }
+        let tmp_path = format!("{}.tmp", self.state_path);
+        fs::write(&tmp_path, serde_json::to_string(&state)?)?;
+        fs::rename(&tmp_path, &self.state_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LogTailer, MAX_READ_PER_TICK};
+    use crate::options::LogConfig;
+
+    use std::{collections::HashMap, fs, io::Write};
+
+    #[test]
+    fn follow_rotation_and_restart() {
+        let dir = std::env::temp_dir().join(format!("speculare-logtail-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.join("access.log").to_string_lossy().into_owned();
+        let state_path = dir.join("state").to_string_lossy().into_owned();
+        fs::write(&log_path, "GET / 500\n").unwrap();
+
+        let mut patterns = HashMap::new();
+        patterns.insert("5xx".to_owned(), " 5\\d\\d$".to_owned());
+        let logs = vec![LogConfig {
+            path: log_path.clone(),
+            patterns,
+        }];
+        // Lines present before the first start are ignored
+        let mut tailer = LogTailer::new(&logs, &state_path);
+        assert_eq!(tailer.collect()[0].count, 0);
+
+        let mut file = fs::OpenOptions::new().append(true).open(&log_path).unwrap();
+        file.write_all(b"GET / 502\nGET / 200\nGET / 503").unwrap();
+        assert_eq!(tailer.collect()[0].count, 1);
+
+        // Rotate: the end of the old file is still read, then the new one from the start
+        file.write_all(b"\n").unwrap();
+        fs::rename(&log_path, format!("{}.1", log_path)).unwrap();
+        fs::write(&log_path, "GET / 504\n").unwrap();
+        assert_eq!(tailer.collect()[0].count, 2);
+
+        // A restart resumes from the saved offset
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&log_path)
+            .unwrap()
+            .write_all(b"GET / 500\n")
+            .unwrap();
+        let mut tailer = LogTailer::new(&logs, &state_path);
+        assert_eq!(tailer.collect()[0].count, 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bounded_reads() {
+        let dir =
+            std::env::temp_dir().join(format!("speculare-logtail-burst-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let log_path = dir.join("burst.log").to_string_lossy().into_owned();
+        let state_path = dir.join("state").to_string_lossy().into_owned();
+        fs::write(&log_path, "").unwrap();
+
+        let mut patterns = HashMap::new();
+        patterns.insert("5xx".to_owned(), " 5\\d\\d$".to_owned());
+        let logs = vec![LogConfig {
+            path: log_path.clone(),
+            patterns,
+        }];
+        let mut tailer = LogTailer::new(&logs, &state_path);
+        assert_eq!(tailer.collect()[0].count, 0);
+
+        // A burst bigger than what is read per harvest is spread over the next ones
+        let lines = (MAX_READ_PER_TICK / 10 + 1000) as i64;
+        fs::write(&log_path, "GET / 500\n".repeat(lines as usize)).unwrap();
+        let first = tailer.collect()[0].count;
+        assert_eq!(first, (MAX_READ_PER_TICK / 10) as i64);
+        assert_eq!(tailer.collect()[0].count, lines - first);
+        assert_eq!(tailer.collect()[0].count, 0);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,2 +1,38 @@
+pub mod aggregate;
+pub use self::aggregate::*;
+
 pub mod data_harvest;
 pub use self::data_harvest::*;
+
+pub mod vmstat;
+pub use self::vmstat::*;
+
+pub mod clock;
+pub use self::clock::*;
+
+pub mod exec;
+pub use self::exec::*;
+
+pub mod inventory;
+pub use self::inventory::*;
+
+pub mod kmsg;
+pub use self::kmsg::*;
+
+pub mod logtail;
+pub use self::logtail::*;
+
+pub mod plugin_loader;
+pub use self::plugin_loader::*;
+
+pub mod plugin_runner;
+pub use self::plugin_runner::*;
+
+pub mod sockets;
+pub use self::sockets::*;
+
+pub mod systemd;
+pub use self::systemd::*;
+
+pub mod telemetry;
+pub use self::telemetry::*;
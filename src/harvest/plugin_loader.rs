@@ -0,0 +1,581 @@
+use super::{
+    plugin_runner::{PluginJob, PluginRunner},
+    telemetry::Telemetry,
+};
+use crate::options::{
+    plugin_sandbox::SandboxedPlugin,
+    plugins_init::{
+        check_plugin_name, is_plugin_file, load_plugin, plugin_config, plugin_file_config,
+        plugin_paths,
+    },
+    Config,
+};
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs::File,
+    io::{Error, ErrorKind},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// A plugin loaded from a file of the plugins_path.
+#[derive(Debug, Clone)]
+struct LoadedPlugin {
+    name: String,
+    version: String,
+}
+
+/// A plugin verified and initialized, ready to be added to the runner.
+struct StartedPlugin {
+    name: String,
+    version: String,
+    job: PluginJob,
+}
+
+/// A plugin being verified and initialized on its own thread, so a slow or hung
+/// init doesn't block the harvests.
+struct StartingPlugin {
+    since: Instant,
+    timeout: Duration,
+    // The plugin previously loaded from the same file, if any
+    previous: Option<LoadedPlugin>,
+    result: Receiver<Result<Option<StartedPlugin>, Error>>,
+}
+
+/// Load the plugins of the plugins_path into the runner, and keep them in sync
+/// with the folder: new files are loaded, removed ones unloaded and replaced ones
+/// reloaded, between two harvests.
+#[derive(Default)]
+pub struct PluginLoader {
+    loaded: HashMap<PathBuf, LoadedPlugin>,
+    // Unloaded plugins whose worker is finishing its collect
+    draining: HashMap<PathBuf, (LoadedPlugin, JoinHandle<()>)>,
+    // Files to (re)load as soon as their previous plugin is gone
+    pending: HashSet<PathBuf>,
+    // Files whose plugin is being initialized
+    starting: HashMap<PathBuf, StartingPlugin>,
+    watcher: Option<File>,
+}
+
+/// Watch `path` for the files written, moved, removed or whose permissions changed.
+#[cfg(target_os = "linux")]
+fn watch_dir(path: &Path) -> Result<File, Error> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt, os::unix::io::FromRawFd};
+
+    let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(Error::last_os_error());
+    }
+    // Safety: fd was just created and is owned by nobody else
+    let file = unsafe { File::from_raw_fd(fd) };
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let mask = libc::IN_CLOSE_WRITE
+        | libc::IN_MOVED_TO
+        | libc::IN_MOVED_FROM
+        | libc::IN_DELETE
+        | libc::IN_ATTRIB;
+    if unsafe { libc::inotify_add_watch(fd, c_path.as_ptr(), mask) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(file)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watch_dir(_path: &Path) -> Result<File, Error> {
+    Err(Error::new(
+        ErrorKind::Other,
+        "inotify is only supported on Linux",
+    ))
+}
+
+/// Read the names of the files changed since the last call, None if some events
+/// were lost and the whole folder must be checked.
+#[cfg(target_os = "linux")]
+fn read_events(watcher: &mut File) -> Result<Option<Vec<PathBuf>>, Error> {
+    use std::{io::Read, mem::size_of, os::unix::ffi::OsStrExt};
+
+    let mut names = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let len = match watcher.read(&mut buf) {
+            Ok(len) => len,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+            Err(err) => return Err(err),
+        };
+        let mut offset = 0;
+        while offset + size_of::<libc::inotify_event>() <= len {
+            // Safety: the kernel only writes whole events
+            let event = unsafe {
+                std::ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event)
+            };
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                return Ok(None);
+            }
+            let start = offset + size_of::<libc::inotify_event>();
+            offset = start + event.len as usize;
+            // The name is padded with NULs
+            let name = &buf[start..offset.min(len)];
+            let name = &name[..name.iter().position(|c| *c == 0).unwrap_or(name.len())];
+            if !name.is_empty() {
+                names.push(PathBuf::from(OsStr::from_bytes(name)));
+            }
+        }
+    }
+    Ok(Some(names))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_events(_watcher: &mut File) -> Result<Option<Vec<PathBuf>>, Error> {
+    Ok(Some(Vec::new()))
+}
+
+/// Get the interval and timeout of the plugin `name`.
+fn schedule(config: &Config, name: &str) -> (u64, Duration) {
+    let plugin_config = plugin_config(config, name);
+    (
+        plugin_config
+            .and_then(|plugin_config| plugin_config.interval)
+            .unwrap_or(1),
+        Duration::from_secs(
+            plugin_config
+                .and_then(|plugin_config| plugin_config.timeout)
+                .or(config.plugins_timeout)
+                .unwrap_or(10),
+        ),
+    )
+}
+
+impl PluginLoader {
+    /// Load the plugins of the plugins_path, and watch it for changes if asked to.
+    pub fn new(config: &Config, runner: &mut PluginRunner, telemetry: &mut Telemetry) -> Self {
+        let mut loader = PluginLoader::default();
+        if config.plugins_watch.unwrap_or(true) {
+            match watch_dir(Path::new(&config.plugins_path)) {
+                Ok(watcher) => loader.watcher = Some(watcher),
+                Err(err) => warn!("cannot watch {} for plugins: {}", config.plugins_path, err),
+            }
+        }
+        match plugin_paths(config) {
+            Ok(paths) => loader.pending.extend(paths),
+            Err(err) => warn!(
+                "cannot read the plugins from {}: {}",
+                config.plugins_path, err
+            ),
+        }
+        loader.sync(config, runner, telemetry);
+        loader
+    }
+
+    /// Verify and initialize the plugin at `path` on its own thread, it's added
+    /// to the runner by `poll` once its init succeeded.
+    fn start(
+        &mut self,
+        path: PathBuf,
+        config: &Config,
+        runner: &PluginRunner,
+        previous: Option<LoadedPlugin>,
+    ) {
+        let timeout = Duration::from_secs(
+            plugin_file_config(config.plugins.as_ref(), &path)
+                .and_then(|plugin_config| plugin_config.timeout)
+                .or(config.plugins_timeout)
+                .unwrap_or(10),
+        );
+        // The exec plugins and the other files' plugins can't be shadowed
+        let taken = runner.names();
+        let (sender, result) = mpsc::channel();
+        let spawned = {
+            let (path, config) = (path.to_owned(), config.clone());
+            thread::Builder::new()
+                .name("plugin-init".to_owned())
+                .spawn(move || {
+                    let _ = sender.send(start_plugin(&path, &config, &taken));
+                })
+        };
+        match spawned {
+            Ok(_) => {
+                self.starting.insert(
+                    path,
+                    StartingPlugin {
+                        since: Instant::now(),
+                        timeout,
+                        previous,
+                        result,
+                    },
+                );
+            }
+            Err(err) => error!("plugin ({:?}) cannot be started: {}", path, err),
+        }
+    }
+
+    /// Add the plugins whose init is over to the runner, and reject the ones
+    /// which failed or timed out.
+    ///
+    /// Return true if the plugins of the runner changed.
+    fn poll(
+        &mut self,
+        config: &Config,
+        runner: &mut PluginRunner,
+        telemetry: &mut Telemetry,
+    ) -> bool {
+        let mut changed = false;
+        for path in self.starting.keys().cloned().collect::<Vec<_>>() {
+            let starting = &self.starting[&path];
+            let result = match starting.result.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) if starting.since.elapsed() < starting.timeout => continue,
+                // The thread is left behind, the plugin is dropped whenever its init returns
+                Err(TryRecvError::Empty) => Err(Error::new(
+                    ErrorKind::TimedOut,
+                    format!("init timed out after {:?}", starting.timeout),
+                )),
+                Err(TryRecvError::Disconnected) => {
+                    Err(Error::new(ErrorKind::Other, "init panicked"))
+                }
+            };
+            let previous = self
+                .starting
+                .remove(&path)
+                .and_then(|starting| starting.previous);
+            // Another plugin may have taken the name meanwhile
+            let result = result.and_then(|plugin| match plugin {
+                Some(plugin) => {
+                    check_plugin_name(&plugin.name, &runner.names()).map(|_| Some(plugin))
+                }
+                None => Ok(None),
+            });
+            match result {
+                Ok(Some(StartedPlugin { name, version, job })) => {
+                    match &previous {
+                        Some(previous) => info!(
+                            "plugin {} reloaded: v{} -> v{}",
+                            name, previous.version, version
+                        ),
+                        None => info!("plugin {} v{} loaded", name, version),
+                    }
+                    let (interval, timeout) = schedule(config, &name);
+                    runner.add(&name, interval, timeout, job);
+                    telemetry.record_plugin_version(&name, &version);
+                    self.loaded.insert(path, LoadedPlugin { name, version });
+                    changed = true;
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    error!("plugin ({:?}) rejected: {}", path, err);
+                    telemetry.record_rejected_plugin(&path.to_string_lossy(), &err.to_string());
+                }
+            }
+        }
+        changed
+    }
+
+    /// Unload the plugins whose file changed, and (re)load the pending files.
+    ///
+    /// Return true if the plugins of the runner changed.
+    fn sync(
+        &mut self,
+        config: &Config,
+        runner: &mut PluginRunner,
+        telemetry: &mut Telemetry,
+    ) -> bool {
+        let mut changed = false;
+        for path in self.pending.drain().collect::<Vec<_>>() {
+            // Wait for the current init of the file before starting another
+            if self.starting.contains_key(&path) {
+                self.pending.insert(path);
+                continue;
+            }
+            // The worker drops the previous plugin once its collect is done, which
+            // must happen before loading it again: the same path would reuse the library.
+            if let Some(plugin) = self.loaded.remove(&path) {
+                if let Some(thread) = runner.remove(&plugin.name) {
+                    self.draining.insert(path.to_owned(), (plugin, thread));
+                }
+                changed = true;
+            }
+            if let Some((plugin, thread)) = self.draining.get(&path) {
+                if !thread.is_finished() {
+                    if !telemetry.is_pending_plugin(&path.to_string_lossy()) {
+                        warn!(
+                            "plugin {} is still collecting, {:?} is reloaded once it's done",
+                            plugin.name, path
+                        );
+                        telemetry.record_pending_plugin(&path.to_string_lossy(), &plugin.name);
+                    }
+                    self.pending.insert(path);
+                    continue;
+                }
+            }
+            let previous = self.draining.remove(&path).map(|(plugin, thread)| {
+                // Make sure the thread is completely gone, with the library
+                let _ = thread.join();
+                telemetry.remove_plugin(&plugin.name);
+                plugin
+            });
+            telemetry.clear_pending_plugin(&path.to_string_lossy());
+            telemetry.clear_rejected_plugin(&path.to_string_lossy());
+
+            if !path.exists() {
+                if let Some(previous) = previous {
+                    info!("plugin {} v{} unloaded", previous.name, previous.version);
+                }
+                continue;
+            }
+            debug!("is {:?} a plugin", path);
+            self.start(path, config, runner, previous);
+        }
+        changed
+    }
+
+    /// The plugins of the runner, as name@version for the ones of the plugins_path.
+    pub fn plugins(&self, runner: &PluginRunner) -> Vec<String> {
+        runner
+            .names()
+            .into_iter()
+            .map(
+                |name| match self.loaded.values().find(|plugin| plugin.name == name) {
+                    Some(plugin) => format!("{}@{}", name, plugin.version),
+                    None => name,
+                },
+            )
+            .collect()
+    }
+
+    /// Apply the changes of the plugins_path since the last call, true if the
+    /// loaded plugins changed.
+    pub fn reload(
+        &mut self,
+        config: &Config,
+        runner: &mut PluginRunner,
+        telemetry: &mut Telemetry,
+    ) -> bool {
+        let events = match &mut self.watcher {
+            Some(watcher) => read_events(watcher),
+            None => Ok(Some(Vec::new())),
+        };
+        let folder = Path::new(&config.plugins_path);
+        match events {
+            Ok(Some(names)) => {
+                for name in names {
+                    // A new signature means the plugin must be verified again
+                    let name = match name.extension() {
+                        Some(extension) if extension == "sig" => name.with_extension(""),
+                        _ => name,
+                    };
+                    if is_plugin_file(&name) {
+                        self.pending.insert(folder.join(name));
+                    }
+                }
+            }
+            Ok(None) => {
+                warn!(
+                    "some changes of {} were lost, checking all the plugins",
+                    config.plugins_path
+                );
+                self.pending.extend(self.loaded.keys().cloned());
+                self.pending
+                    .extend(plugin_paths(config).unwrap_or_default());
+            }
+            Err(err) => {
+                error!("cannot watch {} anymore: {}", config.plugins_path, err);
+                self.watcher = None;
+            }
+        }
+        let mut changed = false;
+        if !self.pending.is_empty() {
+            changed = self.sync(config, runner, telemetry);
+        }
+        self.poll(config, runner, telemetry) || changed
+    }
+}
+
+/// Verify and initialize the plugin at `path`, None if it's disabled.
+fn start_plugin(
+    path: &Path,
+    config: &Config,
+    taken: &[String],
+) -> Result<Option<StartedPlugin>, Error> {
+    let (name, version, job): (String, String, PluginJob) = match &config.plugins_sandbox {
+        // Each plugin in its own process, which is restarted if it crashes
+        Some(sandbox) => match SandboxedPlugin::start(path, config, sandbox, taken)? {
+            Some((name, mut plugin)) => {
+                let version = plugin.version.to_owned();
+                (name, version, Box::new(move || plugin.collect()))
+            }
+            None => return Ok(None),
+        },
+        None => match load_plugin(
+            path,
+            config.plugins.as_ref(),
+            config.plugins_integrity.as_ref(),
+            taken,
+        )? {
+            Some((name, info)) => {
+                let version = info.version.to_owned();
+                (name, version, Box::new(move || info.collect()))
+            }
+            None => return Ok(None),
+        },
+    };
+    Ok(Some(StartedPlugin { name, version, job }))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::{read_events, watch_dir, LoadedPlugin, PluginLoader};
+    use crate::harvest::{plugin_runner::PluginRunner, telemetry::Telemetry};
+    use crate::options::Config;
+
+    use serde_json::json;
+    use std::{
+        env::consts::{DLL_PREFIX, DLL_SUFFIX},
+        fs,
+        os::unix::fs::PermissionsExt,
+        path::PathBuf,
+        sync::mpsc,
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn watch_plugins_path() {
+        let folder = std::env::temp_dir().join("speculare_plugins_watch");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir(&folder).unwrap();
+        let mut watcher = watch_dir(&folder).unwrap();
+        assert_eq!(read_events(&mut watcher).unwrap(), Some(Vec::new()));
+
+        fs::write(folder.join("new.so"), "new").unwrap();
+        fs::rename(folder.join("new.so"), folder.join("renamed.so")).unwrap();
+        fs::remove_file(folder.join("renamed.so")).unwrap();
+        assert_eq!(
+            read_events(&mut watcher).unwrap(),
+            Some(vec![
+                PathBuf::from("new.so"),
+                PathBuf::from("new.so"),
+                PathBuf::from("renamed.so"),
+                PathBuf::from("renamed.so"),
+            ])
+        );
+        let _ = fs::remove_dir_all(folder);
+    }
+
+    /// Reload until the previous plugins are gone and the new ones initialized.
+    fn reload(
+        loader: &mut PluginLoader,
+        config: &Config,
+        runner: &mut PluginRunner,
+        telemetry: &mut Telemetry,
+    ) -> bool {
+        let mut changed = loader.reload(config, runner, telemetry);
+        for _ in 0..100 {
+            if loader.pending.is_empty() && loader.starting.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+            changed |= loader.reload(config, runner, telemetry);
+        }
+        changed
+    }
+
+    #[test]
+    #[ignore = "needs the active_users plugin built (cargo build --workspace)"]
+    fn hot_reload_plugins() {
+        let exe = std::env::current_exe().unwrap();
+        let built = exe
+            .parent()
+            .and_then(|deps| deps.parent())
+            .unwrap()
+            .join(format!("{}active_users{}", DLL_PREFIX, DLL_SUFFIX));
+        let folder =
+            std::env::temp_dir().join(format!("speculare_hot_reload_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir(&folder).unwrap();
+        fs::set_permissions(&folder, fs::Permissions::from_mode(0o755)).unwrap();
+        let plugin = folder.join(format!("active_users{}", DLL_SUFFIX));
+        // Like a package manager would: write aside, then move in place
+        let install = || {
+            let tmp = folder.join(".active_users.tmp");
+            fs::copy(&built, &tmp).unwrap();
+            fs::set_permissions(&tmp, fs::Permissions::from_mode(0o755)).unwrap();
+            fs::rename(&tmp, &plugin).unwrap();
+        };
+        let config: Config = serde_json::from_value(json!({
+            "api_token": "token",
+            "api_url": "https://localhost",
+            "harvest_interval": 1,
+            "syncing_interval": 1,
+            "loadavg_interval": 1,
+            "plugins_path": folder,
+        }))
+        .unwrap();
+        let mut runner = PluginRunner::default();
+        let mut telemetry = Telemetry::default();
+        let mut loader = PluginLoader::new(&config, &mut runner, &mut telemetry);
+        assert!(runner.is_empty());
+        // Added
+        install();
+        assert!(reload(&mut loader, &config, &mut runner, &mut telemetry));
+        assert_eq!(loader.plugins(&runner), vec!["active_users@0.1.0"]);
+        // Replaced
+        install();
+        assert!(reload(&mut loader, &config, &mut runner, &mut telemetry));
+        assert_eq!(loader.plugins(&runner), vec!["active_users@0.1.0"]);
+        assert_eq!(runner.collect(&mut telemetry).len(), 1);
+        // Removed
+        fs::remove_file(&plugin).unwrap();
+        assert!(reload(&mut loader, &config, &mut runner, &mut telemetry));
+        assert!(runner.is_empty());
+        assert!(!reload(&mut loader, &config, &mut runner, &mut telemetry));
+
+        // Another plugin already has the name
+        runner.add(
+            "active_users",
+            1,
+            Duration::from_secs(1),
+            Box::new(|| Ok(json!(1))),
+        );
+        install();
+        assert!(!reload(&mut loader, &config, &mut runner, &mut telemetry));
+        assert_eq!(loader.plugins(&runner), vec!["active_users"]);
+        assert!(runner.remove("active_users").is_some());
+        fs::remove_file(&plugin).unwrap();
+        assert!(!reload(&mut loader, &config, &mut runner, &mut telemetry));
+
+        // The previous plugin is stuck in its collect: the file waits for it
+        let (release, hung) = mpsc::channel::<()>();
+        runner.add(
+            "active_users",
+            1,
+            Duration::from_secs(1),
+            Box::new(move || {
+                let _ = hung.recv();
+                Ok(json!(1))
+            }),
+        );
+        let loaded = LoadedPlugin {
+            name: "active_users".to_owned(),
+            version: "0.0.1".to_owned(),
+        };
+        loader.loaded.insert(plugin.to_owned(), loaded);
+        runner.collect(&mut telemetry);
+        install();
+        assert!(loader.reload(&config, &mut runner, &mut telemetry));
+        assert!(!loader.reload(&config, &mut runner, &mut telemetry));
+        assert!(runner.is_empty());
+        let pending = telemetry.snapshot(0).pending_plugins;
+        assert_eq!(
+            pending.get(&*plugin.to_string_lossy()).unwrap(),
+            "active_users"
+        );
+        release.send(()).unwrap();
+        assert!(reload(&mut loader, &config, &mut runner, &mut telemetry));
+        assert_eq!(loader.plugins(&runner), vec!["active_users@0.1.0"]);
+        assert!(telemetry.snapshot(0).pending_plugins.is_empty());
+        let _ = fs::remove_dir_all(folder);
+    }
+}
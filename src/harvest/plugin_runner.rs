@@ -0,0 +1,268 @@
+use super::telemetry::Telemetry;
+use crate::options::{Plugin, Stale};
+
+use chrono::prelude::{DateTime, Utc};
+use serde_json::Value;
+use std::{
+    io::{Error, ErrorKind},
+    panic::{self, AssertUnwindSafe},
+    sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// How long a harvest waits for the plugins before using their last good value.
+const COLLECT_GRACE: Duration = Duration::from_millis(500);
+
+/// Collect function of a plugin, run on its worker thread.
+pub type PluginJob = Box<dyn FnMut() -> Result<Value, Error> + Send>;
+
+type JobResult = (Duration, Result<Value, Error>);
+
+/// A plugin running on its own thread.
+struct PluginWorker {
+    name: String,
+    // Run every this many harvests
+    interval: i64,
+    timeout: Duration,
+    requests: Sender<()>,
+    results: Receiver<JobResult>,
+    thread: JoinHandle<()>,
+    // When the running collect started, None if idle
+    running_since: Option<Instant>,
+    timed_out: bool,
+    last_good: Option<(DateTime<Utc>, Value)>,
+}
+
+/// Run the plugins on their own worker, so a slow or hung plugin never blocks
+/// the harvest: it is reported with its last good value instead.
+#[derive(Default)]
+pub struct PluginRunner {
+    workers: Vec<PluginWorker>,
+    ticks: i64,
+}
+
+impl PluginWorker {
+    fn spawn(
+        name: &str,
+        interval: u64,
+        timeout: Duration,
+        mut job: PluginJob,
+    ) -> Result<Self, Error> {
+        let (requests, requests_rx) = mpsc::channel::<()>();
+        let (results_tx, results) = mpsc::channel();
+        let thread = thread::Builder::new()
+            .name(format!("plugin-{}", name))
+            .spawn(move || {
+                // Stop once the worker is dropped
+                for () in requests_rx {
+                    let start = Instant::now();
+                    // Only the agent's side of the job can unwind here (exec plugins,
+                    // sandbox protocol): a panic can't cross the extern "C" functions of
+                    // a dylib plugin, it is caught by the SDK or aborts the whole agent.
+                    let res = match panic::catch_unwind(AssertUnwindSafe(&mut job)) {
+                        Ok(res) => res,
+                        Err(_) => Err(Error::new(ErrorKind::Other, "panicked")),
+                    };
+                    if results_tx.send((start.elapsed(), res)).is_err() {
+                        break;
+                    }
+                }
+            })?;
+
+        Ok(PluginWorker {
+            name: name.to_owned(),
+            interval: interval.max(1) as i64,
+            timeout,
+            requests,
+            results,
+            thread,
+            running_since: None,
+            timed_out: false,
+            last_good: None,
+        })
+    }
+
+    /// The last good value, flagged as stale because of `reason`.
+    fn stale(&self, reason: &str) -> Option<Plugin> {
+        self.last_good.as_ref().map(|(collected_at, val)| Plugin {
+            key: self.name.to_owned(),
+            val: val.clone(),
+            stale: Some(Stale {
+                reason: reason.to_owned(),
+                collected_at: *collected_at,
+            }),
+        })
+    }
+
+    /// Handle the result of a collect.
+    fn finish(&mut self, (duration, res): JobResult, telemetry: &mut Telemetry) -> Option<Plugin> {
+        self.running_since = None;
+        self.timed_out = false;
+        telemetry.record_plugin(
+            &self.name,
+            duration,
+            res.as_ref().err().map(|err| err.to_string()),
+        );
+        match res {
+            Ok(val) => {
+                debug!("PLUGIN {} returned: {:?}", self.name, val);
+                self.last_good = Some((Utc::now(), val.clone()));
+                Some(Plugin {
+                    key: self.name.to_owned(),
+                    val,
+                    stale: None,
+                })
+            }
+            Err(err) => {
+                error!("PLUGIN {} failed with: {}", self.name, err);
+                self.stale("error")
+            }
+        }
+    }
+}
+
+impl PluginRunner {
+    /// Start a worker for the plugin `name`, run every `interval` harvests.
+    pub fn add(&mut self, name: &str, interval: u64, timeout: Duration, job: PluginJob) {
+        match PluginWorker::spawn(name, interval, timeout, job) {
+            Ok(worker) => self.workers.push(worker),
+            Err(err) => error!("cannot start the worker of the plugin {}: {}", name, err),
+        }
+    }
+
+    /// Stop the worker of the plugin `name` and return its thread, which drops the
+    /// plugin once its running collect (if any) is done.
+    pub fn remove(&mut self, name: &str) -> Option<JoinHandle<()>> {
+        let idx = self.workers.iter().position(|worker| worker.name == name)?;
+        Some(self.workers.remove(idx).thread)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.workers.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.workers
+            .iter()
+            .map(|worker| worker.name.to_owned())
+            .collect()
+    }
+
+    /// Start the plugins due this harvest and gather the available results.
+    pub fn collect(&mut self, telemetry: &mut Telemetry) -> Vec<Plugin> {
+        let ticks = self.ticks;
+        self.ticks += 1;
+        let now = Instant::now();
+        let mut plugins = Vec::new();
+
+        // Start the idle plugins which are due, the busy ones keep running
+        for worker in &mut self.workers {
+            if worker.running_since.is_none() && ticks % worker.interval == 0 {
+                match worker.requests.send(()) {
+                    Ok(_) => worker.running_since = Some(now),
+                    Err(_) => error!("the worker of the plugin {} is gone", worker.name),
+                }
+            }
+        }
+
+        // Give the ones started now a bit of time, then use the last good value of
+        // the late ones: those already late at the previous harvests are not waited.
+        let deadline = now + COLLECT_GRACE;
+        for worker in &mut self.workers {
+            let running_since = match worker.running_since {
+                Some(running_since) => running_since,
+                None => continue,
+            };
+            let res = if running_since == now {
+                let wait = deadline.saturating_duration_since(Instant::now());
+                worker.results.recv_timeout(wait)
+            } else {
+                worker.results.try_recv().map_err(|err| match err {
+                    TryRecvError::Empty => RecvTimeoutError::Timeout,
+                    TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+                })
+            };
+            match res {
+                Ok(res) => plugins.extend(worker.finish(res, telemetry)),
+                Err(RecvTimeoutError::Timeout) if running_since.elapsed() >= worker.timeout => {
+                    // Only count it once, the plugin might still come back
+                    if !worker.timed_out {
+                        worker.timed_out = true;
+                        error!(
+                            "PLUGIN {} timed out after {:?}",
+                            worker.name, worker.timeout
+                        );
+                        telemetry.record_plugin(
+                            &worker.name,
+                            running_since.elapsed(),
+                            Some("timed out".to_owned()),
+                        );
+                    }
+                    plugins.extend(worker.stale("timeout"));
+                }
+                Err(RecvTimeoutError::Timeout) => plugins.extend(worker.stale("running")),
+                Err(RecvTimeoutError::Disconnected) => {
+                    worker.running_since = None;
+                    error!("the worker of the plugin {} is gone", worker.name);
+                }
+            }
+        }
+
+        plugins
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PluginRunner, COLLECT_GRACE};
+    use crate::harvest::telemetry::Telemetry;
+
+    use serde_json::json;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    #[test]
+    fn slow_plugins_dont_block() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let slow_calls = calls.clone();
+        let mut runner = PluginRunner::default();
+        runner.add("fast", 1, Duration::from_secs(1), Box::new(|| Ok(json!(1))));
+        runner.add(
+            "slow",
+            1,
+            Duration::from_millis(600),
+            Box::new(move || {
+                // Fast the first time, then hangs
+                if slow_calls.fetch_add(1, Ordering::SeqCst) > 0 {
+                    thread::sleep(Duration::from_secs(2));
+                }
+                Ok(json!("ok"))
+            }),
+        );
+        let mut telemetry = Telemetry::default();
+
+        let plugins = runner.collect(&mut telemetry);
+        assert_eq!(plugins.len(), 2);
+        assert!(plugins.iter().all(|plugin| plugin.stale.is_none()));
+
+        // The slow plugin is still running, its last good value is used
+        let plugins = runner.collect(&mut telemetry);
+        let slow = plugins.iter().find(|plugin| plugin.key == "slow").unwrap();
+        assert_eq!(slow.val, json!("ok"));
+        assert_eq!(slow.stale.as_ref().unwrap().reason, "running");
+
+        // Still hung, but only the plugins started this harvest are waited for
+        thread::sleep(COLLECT_GRACE);
+        let start = Instant::now();
+        let plugins = runner.collect(&mut telemetry);
+        assert!(start.elapsed() < COLLECT_GRACE);
+        let slow = plugins.iter().find(|plugin| plugin.key == "slow").unwrap();
+        assert_eq!(slow.stale.as_ref().unwrap().reason, "timeout");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,205 @@
+use serde::Serialize;
+use std::{
+    collections::HashMap,
+    fs,
+    io::Error,
+    net::{Ipv4Addr, Ipv6Addr},
+};
+
+/// Tables of /proc/net to read and the protocol they describe.
+const PROC_NET: &[(&str, &str)] = &[
+    ("tcp", "/proc/net/tcp"),
+    ("tcp6", "/proc/net/tcp6"),
+    ("udp", "/proc/net/udp"),
+    ("udp6", "/proc/net/udp6"),
+];
+
+/// A listening TCP or bound UDP socket and the process owning it.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct ListenSocket {
+    pub protocol: String,
+    pub address: String,
+    pub port: i64,
+    pub pid: Option<i64>,
+    pub process: Option<String>,
+    #[serde(skip)]
+    pub inode: u64,
+}
+
+/// Listening sockets of the host.
+#[derive(Debug, Clone, Serialize)]
+pub struct Sockets {
+    // True if the set differs from the previous harvest
+    pub changed: bool,
+    pub listening: Vec<ListenSocket>,
+}
+
+/// Keep track of the previously seen listening sockets to detect changes.
+#[derive(Default)]
+pub struct SocketsWatcher {
+    previous: Option<Vec<(String, String, i64)>>,
+}
+
+/// Decode an address of /proc/net/* ("0100007F:1F90") into (ip, port).
+///
+/// The ip is written as 32 bits words in host byte order.
+fn parse_address(raw: &str) -> Option<(String, i64)> {
+    let (ip, port) = raw.split_at(raw.find(':')?);
+    let port = i64::from_str_radix(&port[1..], 16).ok()?;
+    let words = (0..ip.len() / 8)
+        .map(|i| u32::from_str_radix(&ip[i * 8..i * 8 + 8], 16))
+        .collect::<Result<Vec<u32>, _>>()
+        .ok()?;
+
+    let ip = match words.len() {
+        1 => Ipv4Addr::from(words[0].to_ne_bytes()).to_string(),
+        4 => {
+            let mut octets = [0u8; 16];
+            for (i, word) in words.iter().enumerate() {
+                octets[i * 4..i * 4 + 4].copy_from_slice(&word.to_ne_bytes());
+            }
+            Ipv6Addr::from(octets).to_string()
+        }
+        _ => return None,
+    };
+    Some((ip, port))
+}
+
+/// Parse a /proc/net/{tcp,udp}[6] table and return the listening sockets.
+pub fn parse_proc_net(content: &str, protocol: &str) -> Vec<ListenSocket> {
+    // TCP_LISTEN for tcp, TCP_CLOSE (not connected) for udp
+    let listen_state = if protocol.starts_with("tcp") {
+        "0A"
+    } else {
+        "07"
+    };
+
+    content
+        .lines()
+        // Skip the header
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10 || fields[3] != listen_state {
+                return None;
+            }
+            let (address, port) = parse_address(fields[1])?;
+            Some(ListenSocket {
+                protocol: protocol.to_owned(),
+                address,
+                port,
+                pid: None,
+                process: None,
+                inode: fields[9].parse().ok()?,
+            })
+        })
+        .collect()
+}
+
+/// Map each socket inode to the pid and name of the process owning it.
+fn get_sockets_owner() -> HashMap<u64, (i64, String)> {
+    let mut owners = HashMap::new();
+    let procs = match fs::read_dir("/proc") {
+        Ok(procs) => procs,
+        Err(_) => return owners,
+    };
+
+    for entry in procs.flatten() {
+        let pid = match entry.file_name().to_string_lossy().parse::<i64>() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+        // Processes of other users can't be inspected without privileges
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+        for fd in fds.flatten() {
+            let link = match fs::read_link(fd.path()) {
+                Ok(link) => link,
+                Err(_) => continue,
+            };
+            let inode = link
+                .to_str()
+                .and_then(|link| link.strip_prefix("socket:["))
+                .and_then(|link| link.strip_suffix(']'))
+                .and_then(|inode| inode.parse::<u64>().ok());
+            if let Some(inode) = inode {
+                owners.entry(inode).or_insert_with(|| {
+                    let comm = fs::read_to_string(entry.path().join("comm")).unwrap_or_default();
+                    (pid, comm.trim().to_owned())
+                });
+            }
+        }
+    }
+
+    owners
+}
+
+/// Get the listening sockets, without their owner.
+fn get_listening() -> Result<Vec<ListenSocket>, Error> {
+    let mut listening = Vec::new();
+    for (protocol, path) in PROC_NET {
+        match fs::read_to_string(path) {
+            Ok(content) => listening.extend(parse_proc_net(&content, protocol)),
+            // IPv6 might be disabled
+            Err(_) if protocol.ends_with('6') => {}
+            Err(err) => return Err(err),
+        }
+    }
+    listening.sort();
+    listening
+        .dedup_by(|a, b| (&a.protocol, &a.address, a.port) == (&b.protocol, &b.address, b.port));
+    Ok(listening)
+}
+
+impl SocketsWatcher {
+    /// Get the listening sockets if `force` is true or if they changed since the last call.
+    pub fn poll(&mut self, force: bool) -> Result<Option<Sockets>, Error> {
+        let mut listening = get_listening()?;
+        let current: Vec<(String, String, i64)> = listening
+            .iter()
+            .map(|sock| (sock.protocol.clone(), sock.address.clone(), sock.port))
+            .collect();
+        // The first poll is not considered as a change
+        let changed = matches!(&self.previous, Some(previous) if *previous != current);
+        self.previous = Some(current);
+        if !force && !changed {
+            return Ok(None);
+        }
+
+        // Only resolve the owners when sending, as walking /proc/*/fd is expensive
+        let owners = get_sockets_owner();
+        for sock in &mut listening {
+            if let Some((pid, process)) = owners.get(&sock.inode) {
+                sock.pid = Some(*pid);
+                sock.process = Some(process.to_owned());
+            }
+        }
+
+        Ok(Some(Sockets { changed, listening }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_proc_net;
+
+    #[test]
+    fn parse_listening() {
+        let tcp = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode\n   \
+            0: 0100007F:BC8F 00000000:0000 0A 00000000:00000000 00:00000000 00000000 65534        0 939 1 0 100 0 0 10 0\n   \
+            1: 0100007F:0016 0200007F:C350 01 00000000:00000000 00:00000000 00000000     0        0 663 1 0 100 0 0 10 0\n";
+        let socks = parse_proc_net(tcp, "tcp");
+        assert_eq!(socks.len(), 1);
+        assert_eq!(socks[0].address, "127.0.0.1");
+        assert_eq!(socks[0].port, 48271);
+        assert_eq!(socks[0].inode, 939);
+
+        let tcp6 = "header\n   \
+            0: 00000000000000000000000001000000:0050 00000000000000000000000000000000:0000 0A 00000000:00000000 00:00000000 00000000 0 0 1234 1 0\n";
+        let socks = parse_proc_net(tcp6, "tcp6");
+        assert_eq!(socks[0].address, "::1");
+        assert_eq!(socks[0].port, 80);
+    }
+}
@@ -0,0 +1,133 @@
+use serde::Serialize;
+use std::{
+    io::{Error, ErrorKind},
+    process::Command,
+};
+
+/// Properties asked to `systemctl show` for each unit.
+const UNIT_PROPERTIES: &str = "Id,ActiveState,SubState,NRestarts,MemoryCurrent";
+
+/// State of a systemd unit at the time of the harvest.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct UnitState {
+    pub name: String,
+    pub active_state: String,
+    pub sub_state: String,
+    pub restarts: Option<i64>,
+    pub memory: Option<i64>,
+}
+
+/// Run systemctl with the args and return its stdout.
+fn systemctl(args: &[&str]) -> Result<String, Error> {
+    let output = Command::new("systemctl")
+        .arg("--no-pager")
+        .args(args)
+        .output()?;
+
+    if !output.status.success() {
+        return Err(Error::new(
+            ErrorKind::Other,
+            format!(
+                "systemctl exited with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+        ));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Parse the output of `systemctl show --property=...` for one or more units.
+///
+/// Each unit is a block of `Key=Value` lines, blocks are separated by an empty line.
+pub fn parse_show(content: &str) -> Vec<UnitState> {
+    let mut units = Vec::new();
+    let mut unit = UnitState::default();
+
+    for line in content.lines().chain(std::iter::once("")) {
+        if line.trim().is_empty() {
+            if !unit.name.is_empty() {
+                units.push(std::mem::take(&mut unit));
+            }
+            continue;
+        }
+        let (key, val) = match line.find('=') {
+            Some(idx) => (&line[..idx], &line[idx + 1..]),
+            None => continue,
+        };
+        match key {
+            "Id" => unit.name = val.to_owned(),
+            "ActiveState" => unit.active_state = val.to_owned(),
+            "SubState" => unit.sub_state = val.to_owned(),
+            // Unset values are either "[not set]" or u64::MAX, which both fail to parse
+            "NRestarts" => unit.restarts = val.parse::<i64>().ok(),
+            "MemoryCurrent" => unit.memory = val.parse::<i64>().ok(),
+            _ => {}
+        }
+    }
+
+    units
+}
+
+/// Parse the output of `systemctl list-units --plain --no-legend` and return the units' name.
+pub fn parse_list_units(content: &str) -> Vec<String> {
+    content
+        .lines()
+        // Failed units might be prefixed by a "●" even in plain mode
+        .filter_map(|line| {
+            line.split_whitespace()
+                .find(|field| *field != "●" && *field != "*")
+        })
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Get the state of the units, or of all failed units if `units` is empty.
+pub fn get_units_state(units: &[String]) -> Result<Vec<UnitState>, Error> {
+    let units = if units.is_empty() {
+        parse_list_units(&systemctl(&[
+            "list-units",
+            "--state=failed",
+            "--plain",
+            "--no-legend",
+        ])?)
+    } else {
+        units.to_vec()
+    };
+    // No failed units, nothing to ask
+    if units.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let property = format!("--property={}", UNIT_PROPERTIES);
+    let mut args = vec!["show", &property];
+    args.extend(units.iter().map(String::as_str));
+
+    Ok(parse_show(&systemctl(&args)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_list_units, parse_show};
+
+    #[test]
+    fn parse_systemctl_output() {
+        let units = parse_show(
+            "Id=nginx.service\nActiveState=active\nSubState=running\nNRestarts=2\nMemoryCurrent=1024\n\n\
+             Id=foo.service\nActiveState=failed\nSubState=failed\nNRestarts=0\nMemoryCurrent=[not set]\n",
+        );
+        assert_eq!(units.len(), 2);
+        assert_eq!(units[0].name, "nginx.service");
+        assert_eq!(units[0].sub_state, "running");
+        assert_eq!(units[0].restarts, Some(2));
+        assert_eq!(units[0].memory, Some(1024));
+        assert_eq!(units[1].active_state, "failed");
+        assert_eq!(units[1].memory, None);
+
+        let failed = parse_list_units(
+            "● foo.service loaded failed failed Foo\nbar.timer loaded failed failed Bar\n",
+        );
+        assert_eq!(failed, vec!["foo.service", "bar.timer"]);
+    }
+}
@@ -0,0 +1,207 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{Error, ErrorKind},
+    time::{Duration, Instant},
+};
+
+/// Execution stats of a plugin.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PluginStats {
+    // Duration (ms) of the last execution
+    pub duration_ms: f64,
+    // Failed executions since the agent started
+    pub failures: i64,
+    // Error (or panic) of the last execution if it failed
+    pub last_error: Option<String>,
+    // Version of the plugins loaded from the plugins_path
+    pub version: Option<String>,
+}
+
+/// What the agent itself costs and how its syncs are going.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct AgentTelemetry {
+    // Duration (ms) of each collector for this harvest
+    pub collectors: BTreeMap<String, f64>,
+    pub plugins: BTreeMap<String, PluginStats>,
+    // Plugin files which couldn't be loaded, with the reason
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub rejected_plugins: BTreeMap<String, String>,
+    // Plugin files waiting for the previous plugin (name) to finish its collect
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub pending_plugins: BTreeMap<String, String>,
+    // Duration (ms) of the last sync request
+    pub sync_latency_ms: Option<f64>,
+    // Counters since the agent started
+    pub sync_retries: i64,
+    pub bytes_sent: i64,
+    pub dropped_samples: i64,
+    // Samples waiting to be sent
+    pub cache_depth: i64,
+    // Resident memory (bytes), cpu time (ms) and usage (% of one cpu) of the agent
+    pub rss: Option<i64>,
+    pub cpu_time_ms: Option<i64>,
+    pub cpu_usage: Option<f64>,
+}
+
+/// Record the telemetry of the agent between harvests.
+#[derive(Debug, Default)]
+pub struct Telemetry {
+    current: AgentTelemetry,
+    // Time and cpu time (ms) of the previous snapshot, to compute the usage
+    last_cpu: Option<(Instant, i64)>,
+}
+
+fn as_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Get the cpu time (user + system, in ticks) from the content of /proc/[pid]/stat.
+fn parse_stat_ticks(stat: &str) -> Option<i64> {
+    // The comm can contain spaces, so only split what's after it
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..].split_whitespace().collect();
+    // utime and stime are the 14th and 15th fields, the state being the 3rd
+    let utime: i64 = fields.get(11)?.parse().ok()?;
+    let stime: i64 = fields.get(12)?.parse().ok()?;
+    Some(utime + stime)
+}
+
+/// Get the resident memory (bytes) and cpu time (ms) of the agent.
+fn get_self_usage() -> Result<(i64, i64), Error> {
+    let invalid = |file| Error::new(ErrorKind::InvalidData, format!("invalid {}", file));
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as i64;
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as i64;
+
+    let rss_pages: i64 = fs::read_to_string("/proc/self/statm")?
+        .split_whitespace()
+        .nth(1)
+        .and_then(|pages| pages.parse().ok())
+        .ok_or_else(|| invalid("/proc/self/statm"))?;
+    let ticks = parse_stat_ticks(&fs::read_to_string("/proc/self/stat")?)
+        .ok_or_else(|| invalid("/proc/self/stat"))?;
+
+    Ok((rss_pages * page_size, ticks * 1000 / ticks_per_sec.max(1)))
+}
+
+impl Telemetry {
+    /// Run `collector` and record how long it took under `name`.
+    pub fn time<T>(&mut self, name: &str, collector: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let res = collector();
+        self.current
+            .collectors
+            .insert(name.to_owned(), as_ms(start.elapsed()));
+        res
+    }
+
+    /// Record an execution of the plugin `name`, with its error if it failed.
+    pub fn record_plugin(&mut self, name: &str, duration: Duration, error: Option<String>) {
+        let stats = self.current.plugins.entry(name.to_owned()).or_default();
+        stats.duration_ms = as_ms(duration);
+        if error.is_some() {
+            stats.failures += 1;
+        }
+        stats.last_error = error;
+    }
+
+    /// Record a plugin file which couldn't be loaded.
+    pub fn record_rejected_plugin(&mut self, path: &str, reason: &str) {
+        self.current
+            .rejected_plugins
+            .insert(path.to_owned(), reason.to_owned());
+    }
+
+    /// Forget the plugin file at `path`, which got loaded or removed since.
+    pub fn clear_rejected_plugin(&mut self, path: &str) {
+        self.current.rejected_plugins.remove(path);
+    }
+
+    /// Record the version of the plugin `name`, which just got loaded.
+    pub fn record_plugin_version(&mut self, name: &str, version: &str) {
+        self.current
+            .plugins
+            .entry(name.to_owned())
+            .or_default()
+            .version = Some(version.to_owned());
+    }
+
+    /// Record a plugin file waiting for the plugin `name` to be done before being reloaded.
+    pub fn record_pending_plugin(&mut self, path: &str, name: &str) {
+        self.current
+            .pending_plugins
+            .insert(path.to_owned(), name.to_owned());
+    }
+
+    /// Check if the plugin file at `path` is waiting for its previous plugin.
+    pub fn is_pending_plugin(&self, path: &str) -> bool {
+        self.current.pending_plugins.contains_key(path)
+    }
+
+    /// Forget the plugin file at `path`, which isn't waiting anymore.
+    pub fn clear_pending_plugin(&mut self, path: &str) {
+        self.current.pending_plugins.remove(path);
+    }
+
+    /// Forget the stats of the plugin `name`, which got unloaded.
+    pub fn remove_plugin(&mut self, name: &str) {
+        self.current.plugins.remove(name);
+    }
+
+    /// Record a sync attempt of `bytes`, which will be retried if it failed.
+    pub fn record_sync(&mut self, latency: Duration, bytes: u64, success: bool) {
+        self.current.sync_latency_ms = Some(as_ms(latency));
+        if success {
+            self.current.bytes_sent += bytes as i64;
+        } else {
+            self.current.sync_retries += 1;
+        }
+    }
+
+    /// Record samples drained from the cache without being sent.
+    pub fn record_dropped(&mut self, count: usize) {
+        self.current.dropped_samples += count as i64;
+    }
+
+    /// Get the telemetry to attach to the Data and start a new harvest.
+    pub fn snapshot(&mut self, cache_depth: usize) -> AgentTelemetry {
+        self.current.cache_depth = cache_depth as i64;
+        // /proc/self is Linux only
+        if cfg!(target_os = "linux") {
+            match get_self_usage() {
+                Ok((rss, cpu_time)) => {
+                    let now = Instant::now();
+                    self.current.rss = Some(rss);
+                    self.current.cpu_time_ms = Some(cpu_time);
+                    self.current.cpu_usage = self.last_cpu.map(|(last, last_cpu_time)| {
+                        let elapsed = as_ms(now.duration_since(last));
+                        if elapsed > 0.0 {
+                            (cpu_time - last_cpu_time) as f64 / elapsed * 100.0
+                        } else {
+                            0.0
+                        }
+                    });
+                    self.last_cpu = Some((now, cpu_time));
+                }
+                Err(err) => error!("[Eating] Agent usage fetching error: {}", err),
+            }
+        }
+
+        let snapshot = self.current.clone();
+        self.current.collectors.clear();
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_stat_ticks;
+
+    #[test]
+    fn stat_ticks() {
+        let stat =
+            "4242 (speculare (cli)) S 1 4242 4242 0 -1 4194560 1207 0 0 0 25 17 0 0 20 0 6 0";
+        assert_eq!(parse_stat_ticks(stat), Some(42));
+        assert_eq!(parse_stat_ticks("4242 (truncated) S 1"), None);
+    }
+}
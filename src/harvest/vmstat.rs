@@ -0,0 +1,104 @@
+use serde::Serialize;
+use std::io::Error;
+
+/// Memory pressure related counters extracted from /proc/vmstat.
+///
+/// All values are cumulative since boot, use `VmStat::delta`
+/// to get the increase between two harvests.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct VmStat {
+    pub pgmajfault: i64,
+    pub pswpin: i64,
+    pub pswpout: i64,
+    pub oom_kill: i64,
+    pub compact_stall: i64,
+    pub compact_fail: i64,
+    pub compact_success: i64,
+    pub thp_fault_alloc: i64,
+    pub thp_fault_fallback: i64,
+    pub thp_collapse_alloc: i64,
+    pub thp_collapse_alloc_failed: i64,
+    pub thp_split_page: i64,
+}
+
+impl VmStat {
+    /// Compute the increase of each counter since `prev`.
+    ///
+    /// If a counter went backward (reboot, overflow), the current value is used.
+    pub fn delta(&self, prev: &VmStat) -> VmStat {
+        let diff = |cur: i64, old: i64| if cur >= old { cur - old } else { cur };
+
+        VmStat {
+            pgmajfault: diff(self.pgmajfault, prev.pgmajfault),
+            pswpin: diff(self.pswpin, prev.pswpin),
+            pswpout: diff(self.pswpout, prev.pswpout),
+            oom_kill: diff(self.oom_kill, prev.oom_kill),
+            compact_stall: diff(self.compact_stall, prev.compact_stall),
+            compact_fail: diff(self.compact_fail, prev.compact_fail),
+            compact_success: diff(self.compact_success, prev.compact_success),
+            thp_fault_alloc: diff(self.thp_fault_alloc, prev.thp_fault_alloc),
+            thp_fault_fallback: diff(self.thp_fault_fallback, prev.thp_fault_fallback),
+            thp_collapse_alloc: diff(self.thp_collapse_alloc, prev.thp_collapse_alloc),
+            thp_collapse_alloc_failed: diff(
+                self.thp_collapse_alloc_failed,
+                prev.thp_collapse_alloc_failed,
+            ),
+            thp_split_page: diff(self.thp_split_page, prev.thp_split_page),
+        }
+    }
+}
+
+/// Parse the content of /proc/vmstat, unknown keys are ignored.
+pub fn parse_vmstat(content: &str) -> VmStat {
+    let mut vmstat = VmStat::default();
+
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        // Each line is formatted as `key value`
+        let (key, val) = match (fields.next(), fields.next().map(str::parse::<i64>)) {
+            (Some(key), Some(Ok(val))) => (key, val),
+            _ => continue,
+        };
+        match key {
+            "pgmajfault" => vmstat.pgmajfault = val,
+            "pswpin" => vmstat.pswpin = val,
+            "pswpout" => vmstat.pswpout = val,
+            "oom_kill" => vmstat.oom_kill = val,
+            "compact_stall" => vmstat.compact_stall = val,
+            "compact_fail" => vmstat.compact_fail = val,
+            "compact_success" => vmstat.compact_success = val,
+            "thp_fault_alloc" => vmstat.thp_fault_alloc = val,
+            "thp_fault_fallback" => vmstat.thp_fault_fallback = val,
+            "thp_collapse_alloc" => vmstat.thp_collapse_alloc = val,
+            "thp_collapse_alloc_failed" => vmstat.thp_collapse_alloc_failed = val,
+            "thp_split_page" => vmstat.thp_split_page = val,
+            _ => {}
+        }
+    }
+
+    vmstat
+}
+
+/// Read and parse /proc/vmstat (Linux only).
+pub fn get_vmstat() -> Result<VmStat, Error> {
+    Ok(parse_vmstat(&std::fs::read_to_string("/proc/vmstat")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_vmstat;
+
+    #[test]
+    fn parse_and_delta() {
+        let prev = parse_vmstat("nr_free_pages 817107\npgmajfault 538\noom_kill 0\npswpout 10\n");
+        assert_eq!(prev.pgmajfault, 538);
+        assert_eq!(prev.pswpout, 10);
+
+        let cur = parse_vmstat("pgmajfault 600\noom_kill 2\npswpout 4\nbroken\n");
+        let delta = cur.delta(&prev);
+        assert_eq!(delta.pgmajfault, 62);
+        assert_eq!(delta.oom_kill, 2);
+        // Counter went backward, keep the current value
+        assert_eq!(delta.pswpout, 4);
+    }
+}
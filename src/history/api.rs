@@ -0,0 +1,161 @@
+use super::store::{parse_time, points_to_json, HistoryStore};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use serde_json::json;
+use std::{collections::HashMap, convert::Infallible, net::SocketAddr, sync::Arc};
+
+/// Decode the %XX and + of a query string component.
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut res = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        match bytes[idx] {
+            b'%' if idx + 2 < bytes.len() => {
+                let byte = std::str::from_utf8(&bytes[idx + 1..idx + 3])
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                match byte {
+                    Some(byte) => {
+                        res.push(byte);
+                        idx += 3;
+                        continue;
+                    }
+                    None => res.push(b'%'),
+                }
+            }
+            b'+' => res.push(b' '),
+            byte => res.push(byte),
+        }
+        idx += 1;
+    }
+    String::from_utf8_lossy(&res).into_owned()
+}
+
+/// Parse the query string of the uri into a map.
+pub fn parse_query(query: Option<&str>) -> HashMap<String, String> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.find('=') {
+            Some(idx) => (
+                percent_decode(&pair[..idx]),
+                percent_decode(&pair[idx + 1..]),
+            ),
+            None => (percent_decode(pair), String::new()),
+        })
+        .collect()
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
+
+/// Handle the requests of the history API.
+///
+/// - GET /metrics: name of the metrics of the last sample
+/// - GET /query?metric=memory.free&from=...&to=...: points of the metric (from/to as
+///   epoch secs or RFC 3339, default to the last hour)
+fn handle(store: &HistoryStore, req: Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET {
+        return json_response(StatusCode::METHOD_NOT_ALLOWED, json!({"error": "GET only"}));
+    }
+
+    match req.uri().path() {
+        "/metrics" => match store.metrics() {
+            Ok(metrics) => json_response(StatusCode::OK, json!(metrics)),
+            Err(err) => json_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                json!({"error": err.to_string()}),
+            ),
+        },
+        "/query" => {
+            let params = parse_query(req.uri().query());
+            let metric = match params.get("metric") {
+                Some(metric) => metric,
+                None => {
+                    return json_response(
+                        StatusCode::BAD_REQUEST,
+                        json!({"error": "missing metric"}),
+                    )
+                }
+            };
+            let now = chrono::Utc::now().timestamp_millis();
+            let to = params
+                .get("to")
+                .and_then(|to| parse_time(to))
+                .unwrap_or(now);
+            let from = params
+                .get("from")
+                .and_then(|from| parse_time(from))
+                .unwrap_or(to - 3600 * 1000);
+            match store.query(metric, from, to) {
+                Ok(points) => json_response(StatusCode::OK, points_to_json(&points)),
+                Err(err) => json_response(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    json!({"error": err.to_string()}),
+                ),
+            }
+        }
+        _ => json_response(StatusCode::NOT_FOUND, json!({"error": "not found"})),
+    }
+}
+
+/// Serve the history API on `addr` (should be a local address, no auth is done).
+pub async fn serve_history(store: Arc<HistoryStore>, addr: SocketAddr) {
+    let make_svc = make_service_fn(move |_conn| {
+        let store = store.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let store = store.clone();
+                // Reading the segments is blocking, don't stall the runtime
+                async move {
+                    Ok::<_, Infallible>(
+                        tokio::task::spawn_blocking(move || handle(&store, req))
+                            .await
+                            .unwrap_or_else(|err| {
+                                json_response(
+                                    StatusCode::INTERNAL_SERVER_ERROR,
+                                    json!({"error": err.to_string()}),
+                                )
+                            }),
+                    )
+                }
+            }))
+        }
+    });
+
+    let server = match Server::try_bind(&addr) {
+        Ok(server) => server,
+        Err(err) => {
+            error!("history API cannot listen on {}: {}", addr, err);
+            return;
+        }
+    };
+    info!("history API listening on {}", addr);
+    if let Err(err) = server.serve(make_svc).await {
+        error!("history API failed: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_query;
+
+    #[test]
+    fn query_string() {
+        let params = parse_query(Some("metric=disks.%2Fhome.avail_space&from=1624615200&to"));
+        assert_eq!(params["metric"], "disks./home.avail_space");
+        assert_eq!(params["from"], "1624615200");
+        assert_eq!(params["to"], "");
+        assert!(parse_query(None).is_empty());
+    }
+}
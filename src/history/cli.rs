@@ -0,0 +1,72 @@
+use crate::options::config;
+
+use chrono::prelude::{TimeZone, Utc};
+use clap::ArgMatches;
+use hyper::{body, Client, Uri};
+use serde_json::Value;
+
+/// Encode a query string component.
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
+
+/// Query the history API of the running agent and print the result.
+///
+/// Without metric, the name of the available metrics are printed instead.
+pub async fn print_history(
+    args: &ArgMatches,
+    app_args: &ArgMatches,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // The API of the agent only listens if history.listen is set
+    let addr = match args.value_of("addr") {
+        Some(addr) => addr.to_owned(),
+        None => config::get_config(app_args)
+            .history
+            .and_then(|history| history.listen)
+            .ok_or("the history API is disabled, set history.listen in the config or use --addr")?,
+    };
+    let since: i64 = args.value_of("since").unwrap_or("60").parse()?;
+
+    let uri: Uri = match args.value_of("metric") {
+        Some(metric) => format!(
+            "http://{}/query?metric={}&from={}",
+            addr,
+            percent_encode(metric),
+            Utc::now().timestamp() - since * 60
+        ),
+        None => format!("http://{}/metrics", addr),
+    }
+    .parse()?;
+
+    let resp = Client::new().get(uri).await?;
+    let status = resp.status();
+    let body: Value = serde_json::from_slice(&body::to_bytes(resp.into_body()).await?)?;
+    if !status.is_success() {
+        return Err(format!("history API answered {}: {}", status, body).into());
+    }
+
+    for item in body.as_array().map(Vec::as_slice).unwrap_or_default() {
+        match item.as_array().map(Vec::as_slice) {
+            // [timestamp, value]
+            Some([t, v]) => println!(
+                "{}\t{}",
+                Utc.timestamp_millis_opt(t.as_i64().unwrap_or_default())
+                    .single()
+                    .map(|date| date.to_rfc3339())
+                    .unwrap_or_default(),
+                v
+            ),
+            _ => println!("{}", item.as_str().unwrap_or_default()),
+        }
+    }
+
+    Ok(())
+}
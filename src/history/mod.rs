@@ -0,0 +1,8 @@
+pub mod api;
+pub use self::api::*;
+
+pub mod cli;
+pub use self::cli::*;
+
+pub mod store;
+pub use self::store::*;
@@ -0,0 +1,249 @@
+use crate::harvest::{aggregate::flatten, data_harvest::Data};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Error, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Number of segments the history is split into, the oldest one is removed when full.
+const SEGMENTS: usize = 10;
+
+/// One sample of the history, only the numeric values are kept.
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    // Epoch in ms
+    t: i64,
+    m: BTreeMap<String, f64>,
+}
+
+struct Writer {
+    segment: u64,
+    lines: usize,
+    file: Option<File>,
+}
+
+/// Bounded on disk time series of the harvested Data.
+///
+/// The samples are appended as JSON lines to segment files, once `max_samples` is
+/// reached the oldest segment is removed, acting as a ring buffer.
+pub struct HistoryStore {
+    dir: PathBuf,
+    segment_size: usize,
+    writer: Mutex<Writer>,
+}
+
+/// Get the segments of the dir, sorted from the oldest to the newest.
+fn list_segments(dir: &Path) -> Result<Vec<(u64, PathBuf)>, Error> {
+    let mut segments: Vec<(u64, PathBuf)> = fs::read_dir(dir)?
+        .flatten()
+        .filter_map(|entry| {
+            let path = entry.path();
+            if path.extension()? != "jsonl" {
+                return None;
+            }
+            let id = path.file_stem()?.to_str()?.parse::<u64>().ok()?;
+            Some((id, path))
+        })
+        .collect();
+    segments.sort();
+    Ok(segments)
+}
+
+impl HistoryStore {
+    /// Open (or create) the history in `dir`, keeping about `max_samples` samples.
+    pub fn open(dir: &str, max_samples: usize) -> Result<Self, Error> {
+        fs::create_dir_all(dir)?;
+        let dir = PathBuf::from(dir);
+        // Continue the last segment if any
+        let (segment, lines) = match list_segments(&dir)?.pop() {
+            Some((id, path)) => (id, BufReader::new(File::open(path)?).lines().count()),
+            None => (0, 0),
+        };
+
+        Ok(HistoryStore {
+            dir,
+            segment_size: (max_samples / SEGMENTS).max(1),
+            writer: Mutex::new(Writer {
+                segment,
+                lines,
+                file: None,
+            }),
+        })
+    }
+
+    fn segment_path(&self, segment: u64) -> PathBuf {
+        self.dir.join(format!("{:010}.jsonl", segment))
+    }
+
+    /// Append the numeric values of `data` to the history.
+    pub fn append(&self, data: &Data) -> Result<(), Error> {
+        let mut leaves = Vec::new();
+        flatten(&serde_json::to_value(data)?, String::new(), &mut leaves);
+        let entry = Entry {
+            t: data.created_at.timestamp_millis(),
+            m: leaves
+                .into_iter()
+                .filter_map(|(path, value)| Some((path, value.as_f64()?)))
+                .collect(),
+        };
+
+        let mut writer = self.writer.lock().unwrap();
+        // Current segment is full, start a new one and drop the oldest ones
+        if writer.lines >= self.segment_size {
+            writer.segment += 1;
+            writer.lines = 0;
+            writer.file = None;
+            let segments = list_segments(&self.dir)?;
+            if segments.len() >= SEGMENTS {
+                for (_, path) in &segments[..=segments.len() - SEGMENTS] {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+        if writer.file.is_none() {
+            writer.file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.segment_path(writer.segment))?,
+            );
+        }
+        let file = writer.file.as_mut().unwrap();
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        writer.lines += 1;
+
+        Ok(())
+    }
+
+    /// Get the (epoch ms, value) of `metric` between `from` and `to` (epoch ms).
+    pub fn query(&self, metric: &str, from: i64, to: i64) -> Result<Vec<(i64, f64)>, Error> {
+        let mut points = Vec::new();
+        for (_, path) in list_segments(&self.dir)? {
+            // The segment might have been removed since we listed them
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(_) => continue,
+            };
+            for line in BufReader::new(file).lines() {
+                // Ignore partially written lines
+                let entry: Entry = match serde_json::from_str(&line?) {
+                    Ok(entry) => entry,
+                    Err(_) => continue,
+                };
+                if entry.t < from || entry.t > to {
+                    continue;
+                }
+                if let Some(value) = entry.m.get(metric) {
+                    points.push((entry.t, *value));
+                }
+            }
+        }
+        Ok(points)
+    }
+
+    /// Get the name of the metrics of the last sample.
+    pub fn metrics(&self) -> Result<Vec<String>, Error> {
+        let path = match list_segments(&self.dir)?.pop() {
+            Some((_, path)) => path,
+            None => return Ok(Vec::new()),
+        };
+        let last = BufReader::new(File::open(path)?)
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Entry>(&line.ok()?).ok())
+            .last();
+        Ok(last.map_or_else(Vec::new, |entry| entry.m.into_keys().collect()))
+    }
+}
+
+/// Convert a query time, either epoch (secs) or RFC 3339, to epoch ms.
+pub fn parse_time(value: &str) -> Option<i64> {
+    match value.parse::<i64>() {
+        Ok(secs) => Some(secs * 1000),
+        Err(_) => chrono::DateTime::parse_from_rfc3339(value)
+            .ok()
+            .map(|date| date.timestamp_millis()),
+    }
+}
+
+/// Convert the points to a JSON value ([[t, v], ...]).
+pub fn points_to_json(points: &[(i64, f64)]) -> Value {
+    Value::from(
+        points
+            .iter()
+            .map(|(t, v)| Value::from(vec![Value::from(*t), Value::from(*v)]))
+            .collect::<Vec<Value>>(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{list_segments, HistoryStore, SEGMENTS};
+    use crate::harvest::data_harvest::Data;
+
+    use chrono::prelude::{TimeZone, Utc};
+    use std::fs;
+
+    fn sample(secs: i64) -> Data {
+        let mut data = Data::with_host(
+            "uuid".to_owned(),
+            "Linux".to_owned(),
+            "6.1".to_owned(),
+            "host".to_owned(),
+        );
+        data.uptime = secs;
+        data.created_at = Utc.timestamp_opt(secs, 0).unwrap();
+        data
+    }
+
+    #[test]
+    fn history_store() {
+        let dir = std::env::temp_dir().join(format!("speculare_history_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.to_str().unwrap();
+        // 2 samples per segment
+        let store = HistoryStore::open(path, 2 * SEGMENTS).unwrap();
+        assert!(store.metrics().unwrap().is_empty());
+
+        for secs in 0..5 {
+            store.append(&sample(secs)).unwrap();
+        }
+        assert_eq!(list_segments(&dir).unwrap().len(), 3);
+        assert!(store.metrics().unwrap().contains(&"uptime".to_owned()));
+        assert_eq!(
+            store.query("uptime", 0, i64::MAX).unwrap(),
+            vec![(0, 0.0), (1000, 1.0), (2000, 2.0), (3000, 3.0), (4000, 4.0)]
+        );
+        // Both bounds are included
+        assert_eq!(
+            store.query("uptime", 1000, 2000).unwrap(),
+            vec![(1000, 1.0), (2000, 2.0)]
+        );
+        assert!(store.query("uptime", 5000, i64::MAX).unwrap().is_empty());
+        assert!(store.query("unknown", 0, i64::MAX).unwrap().is_empty());
+
+        // Full: the oldest segments are removed
+        for secs in 5..30 {
+            store.append(&sample(secs)).unwrap();
+        }
+        assert_eq!(list_segments(&dir).unwrap().len(), SEGMENTS);
+        let points = store.query("uptime", 0, i64::MAX).unwrap();
+        assert_eq!(points.len(), 2 * SEGMENTS);
+        assert_eq!(points.first(), Some(&(10_000, 10.0)));
+        assert_eq!(points.last(), Some(&(29_000, 29.0)));
+
+        // Reopened, the last segment is continued
+        drop(store);
+        let store = HistoryStore::open(path, 2 * SEGMENTS).unwrap();
+        store.append(&sample(30)).unwrap();
+        assert_eq!(list_segments(&dir).unwrap().len(), SEGMENTS);
+        let points = store.query("uptime", 0, i64::MAX).unwrap();
+        assert_eq!(points.first(), Some(&(12_000, 12.0)));
+        assert_eq!(points.last(), Some(&(30_000, 30.0)));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
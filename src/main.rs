@@ -3,24 +3,44 @@ extern crate text_io;
 #[macro_use]
 extern crate log;
 
+mod alerts;
 mod clap;
+mod control;
 mod harvest;
+mod history;
 mod logger;
 mod options;
 
-use harvest::data_harvest::Data;
+use alerts::{AlertEngine, Notifier};
+use chrono::prelude::Utc;
+use control::{fetch_status, serve_control, AgentStatus, SyncResult, DEFAULT_CONTROL_SOCKET};
+use harvest::{
+    aggregate::{aggregate, Aggregate},
+    clock::ClockWatcher,
+    data_harvest::Data,
+    exec::run_exec_plugin,
+    inventory::InventoryWatcher,
+    kmsg::KmsgWatcher,
+    logtail::LogTailer,
+    plugin_loader::PluginLoader,
+    plugin_runner::PluginRunner,
+    sockets::SocketsWatcher,
+    telemetry::Telemetry,
+};
+use history::{print_history, serve_history, HistoryStore};
+use hyper::body::HttpBody;
 use hyper::{Body, Client, Method, Request};
 use hyper_tls::HttpsConnector;
 use options::{
     config::{self},
-    config_prompt,
-    plugins_init::{self},
-    Config, PluginsMap,
+    config_prompt, identity, labels, plugin_sandbox, Config,
 };
+use serde::Serialize;
 use std::{
     io::{Error, ErrorKind},
+    sync::{Arc, Mutex},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 /// Generate the Hyper Client needed for the sync requests
@@ -33,10 +53,10 @@ fn build_client() -> Client<hyper_tls::HttpsConnector<hyper::client::HttpConnect
 }
 
 /// Generate the Request to be sent by the Hyper Client
-fn build_request(
+fn build_request<T: Serialize + ?Sized>(
     api_url: &str,
     token: &str,
-    data_cache: &[Data],
+    data_cache: &T,
 ) -> Result<hyper::Request<hyper::Body>, Error> {
     match Request::builder()
         .method(Method::POST)
@@ -50,6 +70,15 @@ fn build_request(
     }
 }
 
+/// Number of samples waiting to be sent, as is or collapsed into aggregates.
+fn queue_depth(data_cache: &[Data], aggregates: &[Aggregate]) -> usize {
+    data_cache.len()
+        + aggregates
+            .iter()
+            .map(|aggregate| aggregate.samples as usize)
+            .sum::<usize>()
+}
+
 /// Entrypoint which start the process and loop indefinietly.
 ///
 /// No other way to stop it than killing the process (for now).
@@ -67,52 +96,155 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         return Ok(());
     }
 
+    // Print the status of the running agent
+    if let Some(status_args) = args.subcommand_matches("status") {
+        let socket = status_args
+            .value_of("socket")
+            .unwrap_or(DEFAULT_CONTROL_SOCKET);
+        println!("{}", fetch_status(socket).await?.render());
+        return Ok(());
+    }
+
+    // Query the history of the running agent
+    if let Some(history_args) = args.subcommand_matches("history") {
+        return print_history(history_args, &args).await;
+    }
+
+    // Run a single plugin in its sandbox, on behalf of the agent
+    if let Some(host_args) = args.subcommand_matches("plugin-host") {
+        plugin_sandbox::run_plugin_host(host_args)?;
+        return Ok(());
+    }
+
     // Get the config structure
     let config: Config = config::get_config(&args);
 
+    // Status of the agent, served over the control socket
+    let status = Arc::new(Mutex::new(AgentStatus::new(config::get_config_path(&args))));
+    tokio::spawn(serve_control(
+        status.clone(),
+        config
+            .control_socket
+            .clone()
+            .unwrap_or_else(|| DEFAULT_CONTROL_SOCKET.to_owned()),
+    ));
+
     // Build the client instance (*HTTP client)
     let client = build_client();
 
     // Int keeping track of the sending status
     let mut sync_track: i64 = -1;
     let mut load_track: i64 = -1;
+    let mut sockets_track: i64 = -1;
+    let mut inventory_track: i64 = -1;
     // Compute after how many harvest_interval the data has to be sent, and loadavg gathered
     let sync_threshold = (config.harvest_interval * config.syncing_interval) as i64;
     let loadavg_threshold = (config.harvest_interval * config.loadavg_interval) as i64;
+    let sockets_threshold = (config.harvest_interval * config.sockets_interval.unwrap_or(0)) as i64;
+    let inventory_threshold =
+        (config.harvest_interval * config.inventory_interval.unwrap_or(300)) as i64;
 
-    // Get the default Data instance
-    let mut data: Data = Data::default();
+    // Get the default Data instance, identified by the persisted agent id
+    let mut data: Data = Data::new(identity::get_agent_id(&config));
+    // Labels are resolved once and then carried by every Data
+    if let Some(config_labels) = &config.labels {
+        data.labels = labels::get_labels(config_labels);
+        info!("labels: {:?}", data.labels);
+    }
 
     // Syncing memory cache
     let mut data_cache: Vec<Data> = Vec::with_capacity(sync_threshold as usize);
     info!("data_cache with size = {} spaces", sync_threshold);
+    // Aggregates of the windows which couldn't be sent yet
+    let mut aggregates: Vec<Aggregate> = Vec::new();
 
-    // Load Plugins (if any)
-    let mut plugins = std::mem::MaybeUninit::<PluginsMap>::uninit();
-    let mut has_plugins: bool = false;
-    match plugins_init::get_plugins(&config) {
-        Ok(plug_map) => {
-            has_plugins = true;
-            info!("plugins successfully loaded");
-            // Use of unsafe is safe in this case as :
-            //  - we're not reading from the as_mut_ptr
-            //  - write is the first and only one we do to plugins,
-            //    so no fear to loose any previous value without dropping it.
-            unsafe { plugins.as_mut_ptr().write(plug_map) };
-        }
-        Err(plug_err) => {
-            // This initialization as zeroed is used to prevent SEGFault
-            // when reaching end of the program, cause Rust try to drop the value
-            // as we assume plugins as init later.
-            plugins = unsafe { std::mem::zeroed() };
-            warn!("plugins_init throw: {}", plug_err);
+    // Start watching the kernel log source (if any)
+    let kmsg_watcher = match &config.kmsg_path {
+        Some(path) => match KmsgWatcher::start(path, config.kmsg_max_events.unwrap_or(32)) {
+            Ok(watcher) => {
+                info!("watching {} for kernel events", path);
+                Some(watcher)
+            }
+            Err(err) => {
+                error!("cannot watch {} for kernel events: {}", path, err);
+                None
+            }
+        },
+        None => None,
+    };
+
+    // Follow the configured log files (if any)
+    let mut log_tailer = config.logs.as_ref().map(|logs| {
+        LogTailer::new(
+            logs,
+            config
+                .logs_state_path
+                .as_deref()
+                .unwrap_or("/var/lib/speculare/logs.state"),
+        )
+    });
+
+    // Watch the listening sockets (if enabled)
+    let mut sockets_watcher = config.sockets_interval.map(|_| SocketsWatcher::default());
+
+    // Clock synchronization state, and skew compared to the server
+    let mut clock_watcher = ClockWatcher::new(config.max_clock_skew.unwrap_or(2000));
+
+    // Local alerting (if any rules)
+    let mut alert_engine = config.alerts.as_ref().map(|rules| AlertEngine::new(rules));
+    let notifier = Notifier::new(config.alerts_webhook.clone(), config.alerts_command.clone());
+
+    // Local history of the samples, and its query API (if enabled)
+    let history = match &config.history {
+        Some(history) => {
+            match HistoryStore::open(&history.path, history.max_samples.unwrap_or(7 * 24 * 60)) {
+                Ok(store) => {
+                    let store = Arc::new(store);
+                    if let Some(listen) = &history.listen {
+                        match listen.parse() {
+                            Ok(addr) => {
+                                tokio::spawn(serve_history(store.clone(), addr));
+                            }
+                            Err(err) => {
+                                error!("history: invalid listen address {}: {}", listen, err)
+                            }
+                        }
+                    }
+                    Some(store)
+                }
+                Err(err) => {
+                    error!("cannot open the history in {}: {}", history.path, err);
+                    None
+                }
+            }
         }
+        None => None,
     };
-    // //!\\ WARN UNSAFETY //!\\
-    // I assume it's safe to assume_init because I know what I'm doing with it.
-    // It MUST always be used behind a check if has_plugins is true or not.
-    // Used without this protection can lead to undefined behavior and potential overflow/...
-    let plugins = unsafe { plugins.assume_init() };
+
+    // Inventory of the host, sent at startup and then only on change
+    let mut inventory_watcher = InventoryWatcher::default();
+
+    // Self-telemetry of the agent, attached to each Data
+    let mut telemetry = Telemetry::default();
+
+    // Each plugin (dynamic or exec) runs on its own worker
+    let mut plugin_runner = PluginRunner::default();
+    // Load Plugins (if any) and follow the changes of the plugins_path,
+    // the invalid ones are reported in the telemetry
+    let mut plugin_loader = PluginLoader::new(&config, &mut plugin_runner, &mut telemetry);
+    for exec in config.exec_plugins.iter().flatten() {
+        let exec = exec.clone();
+        // The command is killed at its own timeout
+        let timeout = Duration::from_secs(exec.timeout.unwrap_or(10));
+        let name = exec.name.to_owned();
+        plugin_runner.add(
+            &name,
+            exec.interval.unwrap_or(1),
+            timeout,
+            Box::new(move || run_exec_plugin(&exec)),
+        );
+    }
+    status.lock().unwrap().plugins = plugin_loader.plugins(&plugin_runner);
 
     // Start the app loop (collect metrics and send them)
     loop {
@@ -120,19 +252,75 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         sync_track += 1;
         load_track += 1;
         // Refresh / Populate the Data structure
-        data.eat_data(load_track % loadavg_threshold == 0);
+        data.eat_data(load_track % loadavg_threshold == 0, &mut telemetry);
         // Reset loadavg tracker
         if load_track % loadavg_threshold == 0 {
             load_track = 0;
         }
+        // Gather the state of the systemd units if enabled
+        if let Some(units) = &config.systemd_units {
+            telemetry.time("units", || data.eat_units(units));
+        }
+        // Attach the kernel events detected since the previous harvest
+        if let Some(watcher) = &kmsg_watcher {
+            telemetry.time("kernel_events", || data.eat_kernel_events(watcher));
+        }
+        // Count the log lines matching the patterns since the previous harvest
+        if let Some(tailer) = &mut log_tailer {
+            telemetry.time("logs", || data.eat_logs(tailer));
+        }
+        // Get the listening sockets periodically, or as soon as they change
+        if let Some(watcher) = &mut sockets_watcher {
+            sockets_track += 1;
+            let periodic = sockets_track % sockets_threshold.max(1) == 0;
+            telemetry.time("sockets", || data.eat_sockets(watcher, periodic));
+            // Reset sockets tracker
+            if periodic {
+                sockets_track = 0;
+            }
+        }
+        // Check if the inventory changed (always the case for the first harvest)
+        inventory_track += 1;
+        let check_inventory = inventory_track % inventory_threshold.max(1) == 0;
+        telemetry.time("inventory", || {
+            data.eat_inventory(&mut inventory_watcher, check_inventory)
+        });
+        // Reset inventory tracker
+        if check_inventory {
+            inventory_track = 0;
+        }
+        // Check the clock synchronization
+        telemetry.time("clock", || data.eat_clock(&clock_watcher));
+        // Apply the changes of the plugins_path since the previous harvest
+        if plugin_loader.reload(&config, &mut plugin_runner, &mut telemetry) {
+            status.lock().unwrap().plugins = plugin_loader.plugins(&plugin_runner);
+        }
         // Gather data from plugins
         // Only if has_plugins
+        let has_plugins = !plugin_runner.is_empty();
         if has_plugins {
-            data.eat_plugins(&plugins);
+            data.eat_plugins(&mut plugin_runner, &mut telemetry);
+        }
+        // Report on the agent itself
+        data.agent = Some(telemetry.snapshot(queue_depth(&data_cache, &aggregates)));
+        // Evaluate the alert rules against the fresh Data
+        if let Some(engine) = &mut alert_engine {
+            notifier.notify(engine.evaluate(&data));
+        }
+        // Record the sample in the local history
+        if let Some(store) = &history {
+            if let Err(err) = store.append(&data) {
+                error!("history: cannot append the sample: {}", err);
+            }
         }
         // Saving data in a temp var/space if we don't sync it right away
         data_cache.push(data.clone());
         trace!("data_cache filled");
+        {
+            let mut status = status.lock().unwrap();
+            status.last_harvest = Some(data.created_at);
+            status.queue_depth = queue_depth(&data_cache, &aggregates);
+        }
         // Clear the plugin Vec only if has_plugins
         if has_plugins {
             data.clear_plugins();
@@ -140,33 +328,87 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
         // Checking if we should sync
         if sync_track % sync_threshold == 0 {
-            // Sending request to the server
-            let request = build_request(&config.api_url, &config.api_token, &data_cache);
+            // Sending request to the server, collapsing the samples if asked to
+            let request = if config.aggregate.unwrap_or(false) {
+                // Collapse each window on its own, so the backlog keeps its resolution
+                aggregates.extend(aggregate(&data_cache));
+                data_cache.clear();
+                build_request(&config.api_url, &config.api_token, &aggregates)
+            } else {
+                build_request(&config.api_url, &config.api_token, &data_cache)
+            };
             // If the request couldn't be created, exit and print
             if request.is_err() {
                 error!("request builder: {}", request.unwrap_err());
                 break;
             }
             trace!("request is ready to be sent");
+            let request = request.unwrap();
+            let bytes = request.body().size_hint().exact().unwrap_or_default();
 
             // Execute the request
             trace!("sending POST request");
-            match client.request(request.unwrap()).await {
+            let sent_at = Utc::now();
+            let sent_instant = Instant::now();
+            let response = client.request(request).await;
+            // A 4xx/5xx isn't a sync either, its bytes weren't accepted
+            let success = matches!(&response, Ok(resp) if resp.status().is_success());
+            telemetry.record_sync(sent_instant.elapsed(), bytes, success);
+            match response {
                 Ok(resp_body) => {
                     trace!("the POST request resulted in {:?}", resp_body);
+                    status.lock().unwrap().last_sync = Some(SyncResult {
+                        at: sent_at,
+                        success: resp_body.status().is_success(),
+                        http_status: Some(resp_body.status().as_u16()),
+                        error: None,
+                    });
+                    // Compare our clock with the server one
+                    if let Some(date) = resp_body.headers().get(hyper::header::DATE) {
+                        match date.to_str() {
+                            Ok(date) => clock_watcher.record_server_date(date, sent_at, Utc::now()),
+                            Err(err) => error!("invalid Date header: {}", err),
+                        }
+                    }
                     // If no error, clear the data_cache
                     data_cache.clear();
+                    aggregates.clear();
                     trace!("data_cache has cleared");
+                    status.lock().unwrap().queue_depth = 0;
                     // Reset the tracking counter
                     sync_track = 0;
                 }
                 Err(hyper_err) => {
                     error!("the POST request resulted in {:?}", hyper_err);
+                    status.lock().unwrap().last_sync = Some(SyncResult {
+                        at: sent_at,
+                        success: false,
+                        http_status: None,
+                        error: Some(hyper_err.to_string()),
+                    });
                     // If data_cache contains too many items due to previous error
-                    if data_cache.len() as i64 >= sync_threshold * 10 {
-                        // drain the first (older) items to avoid taking too much memory
+                    // drain the first (older) items to avoid taking too much memory
+                    let dropped = if aggregates.len() >= 10 {
+                        aggregates
+                            .drain(0..2)
+                            .map(|aggregate| aggregate.samples as usize)
+                            .sum()
+                    } else if data_cache.len() as i64 >= sync_threshold * 10 {
                         data_cache.drain(0..(sync_threshold * 2) as usize);
-                        warn!("draining 0..{} items of the data_cache", sync_threshold * 2)
+                        (sync_threshold * 2) as usize
+                    } else {
+                        0
+                    };
+                    if dropped > 0 {
+                        telemetry.record_dropped(dropped);
+                        {
+                            let mut status = status.lock().unwrap();
+                            status.dropped_samples += dropped as u64;
+                            status.queue_depth = queue_depth(&data_cache, &aggregates);
+                        }
+                        warn!("draining the {} oldest samples of the data_cache", dropped);
+                        // The inventory might have been drained, send it again
+                        inventory_watcher.reset();
                     }
                 }
             }
@@ -4,15 +4,20 @@ use clap::ArgMatches;
 use std::fs::File;
 use std::io::BufReader;
 
+/// Get the path of the config, from the args or the default one.
+pub fn get_config_path(args: &ArgMatches) -> &str {
+    if args.is_present("path") {
+        args.value_of("path").unwrap()
+    } else {
+        "/usr/share/speculare/configs/speculare.config"
+    }
+}
+
 /// Get the correct path for the config, open it and read it to the Config struct
 /// which is then returned.
 pub fn get_config(args: &ArgMatches) -> Config {
     // Determine the path of the config
-    let config_path = if args.is_present("path") {
-        args.value_of("path").unwrap()
-    } else {
-        "/usr/share/speculare/configs/speculare.config"
-    };
+    let config_path = get_config_path(args);
 
     // Open the config_file as File
     let config_file = match File::open(&config_path) {
@@ -109,6 +109,29 @@ pub fn get_config_prompt() {
         syncing_interval,
         loadavg_interval,
         plugins_path: plug_path.to_owned(),
+        systemd_units: None,
+        kmsg_path: None,
+        kmsg_max_events: None,
+        logs: None,
+        logs_state_path: None,
+        sockets_interval: None,
+        inventory_interval: None,
+        agent_id: None,
+        agent_id_path: None,
+        labels: None,
+        max_clock_skew: None,
+        alerts: None,
+        alerts_webhook: None,
+        alerts_command: None,
+        aggregate: None,
+        history: None,
+        control_socket: None,
+        plugins_timeout: None,
+        plugins_watch: None,
+        plugins: None,
+        plugins_integrity: None,
+        plugins_sandbox: None,
+        exec_plugins: None,
     };
     // Create the configs folder
     match create_dir_all(conf_path) {
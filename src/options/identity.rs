@@ -0,0 +1,205 @@
+use crate::Config;
+
+use serde::{Deserialize, Serialize};
+use std::{fs, io::Error, path::Path};
+use sys_metrics::host::get_uuid;
+
+/// DMI UUIDs some firmwares ship on every machine.
+const PLACEHOLDER_UUIDS: &[&str] = &[
+    "00000000-0000-0000-0000-000000000000",
+    "ffffffff-ffff-ffff-ffff-ffffffffffff",
+    "03000200-0400-0500-0006-000700080009",
+    "00020003-0004-0005-0006-000700080009",
+    "not settable",
+    "not present",
+];
+
+/// Identity persisted on disk, with the fingerprint of the host it was generated on.
+#[derive(Debug, Serialize, Deserialize)]
+struct AgentId {
+    id: String,
+    // Hashed, the machine-id is confidential
+    machine_id: Option<String>,
+    dmi_uuid: Option<String>,
+}
+
+/// Read a file and trim it, None if it doesn't exist or is empty.
+fn read_trimmed<P: AsRef<Path>>(path: P) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|content| content.trim().to_owned())
+        .filter(|content| !content.is_empty())
+}
+
+/// Get the machine-id of the host (systemd or dbus one).
+fn get_machine_id() -> Option<String> {
+    read_trimmed("/etc/machine-id").or_else(|| read_trimmed("/var/lib/dbus/machine-id"))
+}
+
+/// Hash the machine-id for `usage`, so it's never exposed as is.
+fn hash_machine_id(usage: &str, machine_id: &str) -> String {
+    sha1::Sha1::from(format!("{}:{}", usage, machine_id))
+        .digest()
+        .to_string()
+}
+
+/// Generate a random identifier.
+fn random_id() -> String {
+    match read_trimmed("/proc/sys/kernel/random/uuid") {
+        Some(uuid) => uuid.replace('-', ""),
+        // Not on Linux, derive it from things unlikely to be shared
+        None => sha1::Sha1::from(format!(
+            "{:?}{}{:?}",
+            std::time::SystemTime::now(),
+            std::process::id(),
+            std::thread::current().id()
+        ))
+        .digest()
+        .to_string(),
+    }
+}
+
+/// Id used before the identity was persisted (the DMI UUID), if it's not blank nor
+/// one of the placeholders shared by many machines.
+fn legacy_id(uuid: &Result<String, Error>) -> Option<String> {
+    match uuid {
+        Ok(uuid)
+            if !uuid.is_empty()
+                && !PLACEHOLDER_UUIDS.contains(&uuid.to_ascii_lowercase().as_str()) =>
+        {
+            Some(uuid.to_owned())
+        }
+        _ => None,
+    }
+}
+
+/// Return true if the persisted identity was generated on another host.
+///
+/// The hostname is not taken into account as renaming a host must not change its identity.
+fn is_clone(saved: &AgentId, current: &AgentId) -> bool {
+    let differ =
+        |a: &Option<String>, b: &Option<String>| matches!((a, b), (Some(a), Some(b)) if a != b);
+    differ(&saved.machine_id, &current.machine_id) || differ(&saved.dmi_uuid, &current.dmi_uuid)
+}
+
+/// Get the identity of the agent, generating and persisting it on the first run.
+///
+/// The config can pin an explicit id with `agent_id` (ex: the DMI UUID used by the previous
+/// versions, to keep the history of an upgraded host). Otherwise the first run derives it
+/// from the machine-id, or the DMI UUID if there is none. A random one is generated if the
+/// host has been cloned.
+pub fn get_agent_id(config: &Config) -> String {
+    if let Some(id) = config.agent_id.as_ref().filter(|id| !id.is_empty()) {
+        return id.to_owned();
+    }
+
+    let path = config
+        .agent_id_path
+        .as_deref()
+        .unwrap_or("/var/lib/speculare/agent_id");
+    let uuid = get_uuid();
+    let machine_id = get_machine_id();
+    let mut current = AgentId {
+        id: String::new(),
+        machine_id: machine_id
+            .as_ref()
+            .map(|machine_id| hash_machine_id("speculare-fingerprint", machine_id)),
+        // UUID can be empty on some Linux platform (such as WSL)
+        dmi_uuid: uuid.as_ref().ok().filter(|uuid| !uuid.is_empty()).cloned(),
+    };
+
+    let mut saved: Option<AgentId> = fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok());
+    // Files saved by the previous versions have the machine-id as is, hash it
+    let mut raw_machine_id = false;
+    if let Some(saved) = &mut saved {
+        if saved.machine_id.is_some() && saved.machine_id == machine_id {
+            saved.machine_id = current.machine_id.clone();
+            raw_machine_id = true;
+        }
+    }
+    current.id = match saved {
+        Some(saved) if !is_clone(&saved, &current) => {
+            // Nothing changed, no need to persist it again
+            if !raw_machine_id
+                && saved.machine_id == current.machine_id
+                && saved.dmi_uuid == current.dmi_uuid
+            {
+                return saved.id;
+            }
+            // Same host, but a part of the fingerprint appeared/disappeared
+            saved.id
+        }
+        Some(saved) => {
+            warn!(
+                "the agent id {} was generated on another host (cloned?), generating a new one",
+                saved.id
+            );
+            random_id()
+        }
+        // Cloned VMs can share their DMI UUID, not their machine-id
+        None => match (machine_id.as_ref(), legacy_id(&uuid)) {
+            (Some(machine_id), _) => hash_machine_id("speculare", machine_id),
+            (None, Some(uuid)) => uuid,
+            (None, None) => random_id(),
+        },
+    };
+
+    if let Some(folder) = Path::new(path).parent() {
+        let _ = fs::create_dir_all(folder);
+    }
+    match serde_json::to_string(&current)
+        .map_err(Error::from)
+        .and_then(|content| fs::write(path, content))
+    {
+        Ok(_) => info!("agent id {} saved to {}", current.id, path),
+        Err(err) => error!("cannot save the agent id to {}: {}", path, err),
+    }
+    current.id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_clone, legacy_id, AgentId};
+
+    use std::io::{Error, ErrorKind};
+
+    #[test]
+    fn detect_clone() {
+        let host = |machine_id: Option<&str>, dmi_uuid: Option<&str>| AgentId {
+            id: String::new(),
+            machine_id: machine_id.map(str::to_owned),
+            dmi_uuid: dmi_uuid.map(str::to_owned),
+        };
+        let saved = host(Some("aaa"), Some("111"));
+        assert!(!is_clone(&saved, &host(Some("aaa"), Some("111"))));
+        assert!(!is_clone(&saved, &host(Some("aaa"), None)));
+        // Cloned VM keeping the machine-id, but with a new DMI UUID
+        assert!(is_clone(&saved, &host(Some("aaa"), Some("222"))));
+        // Cloned VM with the same DMI UUID, but a regenerated machine-id
+        assert!(is_clone(&saved, &host(Some("bbb"), Some("111"))));
+    }
+
+    #[test]
+    fn unique_legacy_id() {
+        assert_eq!(
+            legacy_id(&Ok("4c4c4544".to_owned())).as_deref(),
+            Some("4c4c4544")
+        );
+        // Blank UUID (WSL) or shipped by the firmware on every machine
+        assert_eq!(legacy_id(&Ok(String::new())), None);
+        assert_eq!(
+            legacy_id(&Ok("03000200-0400-0500-0006-000700080009".to_owned())),
+            None
+        );
+        assert_eq!(
+            legacy_id(&Ok("FFFFFFFF-FFFF-FFFF-FFFF-FFFFFFFFFFFF".to_owned())),
+            None
+        );
+        assert_eq!(
+            legacy_id(&Err(Error::new(ErrorKind::Other, "no uuid"))),
+            None
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fs,
+    process::Command,
+};
+
+/// Replace each `${VAR}` (or `${VAR:-default}`) in `value` by the env variable.
+///
+/// Unknown variables without default are replaced by an empty string.
+pub fn interpolate(value: &str) -> String {
+    let mut res = String::with_capacity(value.len());
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            // Not closed, keep it as is
+            None => break,
+        };
+        res.push_str(&rest[..start]);
+        let expr = &rest[start + 2..end];
+        let (name, default) = match expr.find(":-") {
+            Some(idx) => (&expr[..idx], &expr[idx + 2..]),
+            None => (expr, ""),
+        };
+        res.push_str(&env::var(name).unwrap_or_else(|_| default.to_owned()));
+        rest = &rest[end + 1..];
+    }
+    res.push_str(rest);
+
+    res
+}
+
+/// Compute the value of a label.
+///
+/// - `file:/path` is replaced by the (trimmed) content of the file
+/// - `cmd:command` is replaced by the (trimmed) stdout of the command (run by sh)
+/// - anything else is used as is
+///
+/// Env variables are interpolated before that.
+fn resolve(value: &str) -> Result<String, String> {
+    let value = interpolate(value);
+
+    if let Some(path) = value.strip_prefix("file:") {
+        return fs::read_to_string(path)
+            .map(|content| content.trim().to_owned())
+            .map_err(|err| format!("cannot read {}: {}", path, err));
+    }
+    if let Some(cmd) = value.strip_prefix("cmd:") {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(cmd)
+            .output()
+            .map_err(|err| format!("cannot run `{}`: {}", cmd, err))?;
+        if !output.status.success() {
+            return Err(format!("`{}` exited with {}", cmd, output.status));
+        }
+        return Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned());
+    }
+
+    Ok(value)
+}
+
+/// Resolve the labels of the config, the ones that failed are skipped.
+pub fn get_labels(labels: &HashMap<String, String>) -> BTreeMap<String, String> {
+    labels
+        .iter()
+        .filter_map(|(key, value)| match resolve(value) {
+            Ok(value) => Some((key.to_owned(), value)),
+            Err(err) => {
+                error!("label {} skipped: {}", key, err);
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::get_labels;
+
+    use std::collections::HashMap;
+
+    #[test]
+    fn resolve_labels() {
+        std::env::set_var("SPECULARE_TEST_ENV", "prod");
+        let mut labels = HashMap::new();
+        labels.insert("env".to_owned(), "${SPECULARE_TEST_ENV}-eu".to_owned());
+        labels.insert("team".to_owned(), "${SPECULARE_TEST_NOPE:-ops}".to_owned());
+        labels.insert("role".to_owned(), "cmd:echo web".to_owned());
+        labels.insert(
+            "broken".to_owned(),
+            "file:/nonexistent/speculare".to_owned(),
+        );
+
+        let labels = get_labels(&labels);
+        assert_eq!(labels["env"], "prod-eu");
+        assert_eq!(labels["team"], "ops");
+        assert_eq!(labels["role"], "web");
+        assert!(!labels.contains_key("broken"));
+    }
+}
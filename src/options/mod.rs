@@ -1,9 +1,7 @@
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, io::Error};
+use std::collections::HashMap;
 
-pub type PluginsMap = HashMap<String, PluginInfo>;
-
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub api_token: String,
     pub api_url: String,
@@ -11,18 +9,161 @@ pub struct Config {
     pub syncing_interval: u64,
     pub loadavg_interval: u64,
     pub plugins_path: String,
+    // Units to watch, all failed units if empty, disabled if None
+    pub systemd_units: Option<Vec<String>>,
+    // Kernel log source to watch for events (/dev/kmsg, /var/log/kern.log, ...)
+    pub kmsg_path: Option<String>,
+    pub kmsg_max_events: Option<usize>,
+    // Log files to follow and where to persist their read offsets
+    pub logs: Option<Vec<LogConfig>>,
+    pub logs_state_path: Option<String>,
+    // Send the listening sockets every harvest_interval * this value (and on change)
+    pub sockets_interval: Option<u64>,
+    // Check if the inventory changed every harvest_interval * this value
+    pub inventory_interval: Option<u64>,
+    // Pin the identity of the agent, or where to persist the generated one
+    pub agent_id: Option<String>,
+    pub agent_id_path: Option<String>,
+    // Labels attached to every Data, values support ${ENV}, file: and cmd:
+    pub labels: Option<HashMap<String, String>>,
+    // Max clock skew (ms) tolerated before flagging the clock as skewed
+    pub max_clock_skew: Option<i64>,
+    // Alert rules evaluated locally, and where to deliver their notifications
+    pub alerts: Option<Vec<AlertRule>>,
+    pub alerts_webhook: Option<String>,
+    pub alerts_command: Option<String>,
+    // Send min/max/avg/last/p95 (or sum) of each metric per sync window instead of every sample
+    pub aggregate: Option<bool>,
+    // Keep a local history of the samples, queryable over HTTP
+    pub history: Option<HistoryConfig>,
+    // Unix socket serving the status of the agent, default to /run/speculare.sock
+    pub control_socket: Option<String>,
+    // Time (secs) after which a running plugin is reported as timed out (default 10)
+    pub plugins_timeout: Option<u64>,
+    // Load, unload and reload the plugins as plugins_path changes (default true)
+    pub plugins_watch: Option<bool>,
+    // Config of the plugins, by name
+    pub plugins: Option<HashMap<String, PluginConfig>>,
+    // Checks of the plugin files before loading them (ownership is always checked)
+    pub plugins_integrity: Option<IntegrityConfig>,
+    // Run each plugin in its own restricted process instead of in the agent
+    pub plugins_sandbox: Option<SandboxConfig>,
+    // External commands whose output is merged with the plugins
+    pub exec_plugins: Option<Vec<ExecPluginConfig>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogConfig {
+    pub path: String,
+    // Name of the counter => regex to match against each line
+    pub patterns: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PluginConfig {
+    // Load the plugin (default true)
+    pub enabled: Option<bool>,
+    // Run every harvest_interval * this value (default 1)
+    pub interval: Option<u64>,
+    // Override plugins_timeout for this plugin
+    pub timeout: Option<u64>,
+    // Passed as is (JSON) to the init of the plugin
+    pub settings: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IntegrityConfig {
+    // SHA-256 (hex) of the plugins allowed to load, any if None
+    pub sha256: Option<Vec<String>>,
+    // PEM public key checking the detached signature (<plugin>.sig) of each plugin
+    pub public_key: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SandboxConfig {
+    // User the plugins run as, required when the agent runs as root
+    pub user: Option<String>,
+    // Max address space (MB) of a plugin
+    pub max_memory: Option<u64>,
+    // Max CPU time (secs) of a plugin, its process is restarted once reached
+    pub max_cpu: Option<u64>,
+    // Max number of files a plugin can open
+    pub max_files: Option<u64>,
+    // Deny the syscalls a plugin doesn't need (mount, ptrace, ...), default true
+    pub seccomp: Option<bool>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExecPluginConfig {
+    pub name: String,
+    pub command: String,
+    pub args: Option<Vec<String>>,
+    pub env: Option<HashMap<String, String>>,
+    // Run every harvest_interval * this value (default 1)
+    pub interval: Option<u64>,
+    // Kill the command after this many secs (default 10)
+    pub timeout: Option<u64>,
+    // Format of the output: json, influx or nagios, guessed if None
+    pub format: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryConfig {
+    // Directory of the history segments
+    pub path: String,
+    // Address of the query API (ex: 127.0.0.1:9764), disabled if None
+    pub listen: Option<String>,
+    // Approximate number of samples to keep, default to 1 week at 1 sample/min
+    pub max_samples: Option<usize>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AlertRule {
+    pub name: String,
+    // Path of the value in the Data (memory.free, disks./.avail_space, plugins.*.val, ...)
+    pub metric: String,
+    // Path of the value to divide the metric with, the metric becomes a percentage
+    pub ratio_of: Option<String>,
+    // One of >, >=, <, <=, ==, !=, matches (regex)
+    pub op: String,
+    pub threshold: serde_json::Value,
+    // Value to cross back before resolving (hysteresis), default to the threshold
+    pub recover: Option<f64>,
+    // How long (secs) the condition must hold before firing
+    pub for_secs: Option<i64>,
 }
 
 #[derive(Debug)]
 pub struct PluginInfo {
+    // Keep the library loaded as long as its symbols are used
     pub lib: libloading::Library,
-    pub func: fn() -> Result<String, Error>,
+    // The verified file the library was loaded from (through its fd), kept open
+    // so that fd isn't reused by another plugin while this one is loaded
+    pub file: std::fs::File,
+    pub version: String,
+    pub init: plugin_abi::InitFn,
+    pub collect: plugin_abi::CollectFn,
+    pub shutdown: plugin_abi::ShutdownFn,
+    pub free: plugin_abi::FreeFn,
+    // Only an initialized plugin gets shut down
+    pub initialized: bool,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Plugin {
     pub key: String,
-    pub val: String,
+    // Whatever the plugin returned (number, string, map, list of metrics, ...)
+    pub val: serde_json::Value,
+    // Set when val is the last good value (the plugin failed, is late or timed out)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stale: Option<Stale>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Stale {
+    // error, running or timeout
+    pub reason: String,
+    pub collected_at: chrono::DateTime<chrono::Utc>,
 }
 
 pub mod config;
@@ -31,5 +172,20 @@ pub use self::config::*;
 pub mod config_prompt;
 pub use self::config_prompt::*;
 
+pub mod identity;
+pub use self::identity::*;
+
+pub mod labels;
+pub use self::labels::*;
+
+pub mod plugin_abi;
+pub use self::plugin_abi::*;
+
+pub mod plugin_integrity;
+pub use self::plugin_integrity::*;
+
+pub mod plugin_sandbox;
+pub use self::plugin_sandbox::*;
+
 pub mod plugins_init;
 pub use self::plugins_init::*;
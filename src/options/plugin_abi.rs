@@ -0,0 +1,83 @@
+use super::PluginInfo;
+
+pub use speculare_plugin::abi::*;
+
+use serde_json::Value;
+use std::{
+    ffi::{CStr, CString},
+    io::{Error, ErrorKind},
+    os::raw::c_char,
+};
+
+/// Copy a C string owned by the plugin, None if null.
+///
+/// # Safety
+/// `ptr` must be null or point to a NUL terminated string.
+pub unsafe fn copy_c_str(ptr: *const c_char) -> Option<String> {
+    if ptr.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(ptr).to_string_lossy().into_owned())
+    }
+}
+
+impl PluginInfo {
+    /// Copy and release the result returned by the plugin.
+    fn take_result(&self, res: SpeculareResult) -> Result<Option<String>, Error> {
+        // Safety: data comes from the plugin, and is released right after the copy
+        let data = unsafe { copy_c_str(res.data) };
+        if !res.data.is_null() {
+            unsafe { (self.free)(res.data) };
+        }
+        match res.status {
+            STATUS_OK => Ok(data),
+            status => Err(Error::new(
+                ErrorKind::Other,
+                format!(
+                    "{}: {}",
+                    if status == STATUS_PANIC {
+                        "panicked"
+                    } else {
+                        "failed"
+                    },
+                    data.unwrap_or_else(|| "unknown error".to_owned())
+                ),
+            )),
+        }
+    }
+
+    /// Call the init function of the plugin with its settings (as JSON).
+    pub fn init(&mut self, settings: &str) -> Result<(), Error> {
+        let settings = CString::new(settings)?;
+        // Safety: the symbols were resolved from self.lib which is still loaded,
+        // and their signatures are guaranteed by the ABI version check.
+        let res = unsafe { (self.init)(settings.as_ptr()) };
+        self.take_result(res)?;
+        self.initialized = true;
+        Ok(())
+    }
+
+    /// Call the collect function of the plugin and parse its JSON result.
+    pub fn collect(&self) -> Result<Value, Error> {
+        // Safety: same as for init
+        let res = unsafe { (self.collect)() };
+        let data = self
+            .take_result(res)?
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "no data returned"))?;
+        serde_json::from_str(&data).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid JSON returned: {}", err),
+            )
+        })
+    }
+}
+
+impl Drop for PluginInfo {
+    fn drop(&mut self) {
+        // Let the plugin clean up before its library gets unloaded
+        if self.initialized {
+            unsafe { (self.shutdown)() };
+        }
+    }
+}
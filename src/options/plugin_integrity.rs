@@ -0,0 +1,204 @@
+use super::IntegrityConfig;
+
+use openssl::{
+    error::ErrorStack,
+    hash::MessageDigest,
+    pkey::{Id, PKey},
+    sha::sha256,
+    sign::Verifier,
+};
+use std::{
+    ffi::OsString,
+    fs::{self, File, Metadata, OpenOptions},
+    io::{Error, ErrorKind, Read},
+    os::unix::fs::{MetadataExt, OpenOptionsExt},
+    path::{Path, PathBuf},
+};
+
+/// Path of the detached signature of the plugin at `path` (`<path>.sig`).
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut sig = OsString::from(path.as_os_str());
+    sig.push(".sig");
+    PathBuf::from(sig)
+}
+
+/// Check that only root or the agent itself can write `path`, whose metadata is `meta`.
+fn check_owner(path: &Path, meta: &Metadata) -> Result<(), Error> {
+    let agent_uid = unsafe { libc::geteuid() };
+    if meta.uid() != 0 && meta.uid() != agent_uid {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{:?} is owned by uid {}", path, meta.uid()),
+        ));
+    }
+    // In a sticky folder the others can't replace the files they don't own
+    let sticky = meta.is_dir() && meta.mode() & 0o1000 != 0;
+    if meta.mode() & 0o022 != 0 && !sticky {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            format!("{:?} is writable by group or others", path),
+        ));
+    }
+    Ok(())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Check the detached `signature` of `content` with the PEM public key at `public_key`.
+fn verify_signature(public_key: &str, content: &[u8], signature: &[u8]) -> Result<(), Error> {
+    let to_err = |err: ErrorStack| Error::new(ErrorKind::InvalidData, err);
+    let key = PKey::public_key_from_pem(&fs::read(public_key)?).map_err(to_err)?;
+    // Ed25519 signs the content itself, the other keys a SHA-256 of it
+    let valid = if key.id() == Id::ED25519 {
+        Verifier::new_without_digest(&key)
+            .and_then(|mut verifier| verifier.verify_oneshot(signature, content))
+    } else {
+        Verifier::new(MessageDigest::sha256(), &key).and_then(|mut verifier| {
+            verifier.update(content)?;
+            verifier.verify(signature)
+        })
+    }
+    .map_err(to_err)?;
+
+    if !valid {
+        return Err(Error::new(ErrorKind::PermissionDenied, "invalid signature"));
+    }
+    Ok(())
+}
+
+/// Open the plugin at `path` after checking its folders, without following symlinks.
+fn open_plugin_file(path: &Path) -> Result<File, Error> {
+    if fs::symlink_metadata(path)?.file_type().is_symlink() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("{:?} is a symlink", path),
+        ));
+    }
+    let name = path
+        .file_name()
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no file name"))?;
+    // Whoever can write one of the folders could swap the file
+    let folder = match path.parent() {
+        Some(folder) if folder != Path::new("") => folder.canonicalize()?,
+        _ => std::env::current_dir()?,
+    };
+    for ancestor in folder.ancestors() {
+        check_owner(ancestor, &fs::metadata(ancestor)?)?;
+    }
+    // Everything is checked and read through this fd, the path might change
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(folder.join(name))
+}
+
+/// Verify the plugin at `path` before it gets loaded: the ownership and permissions
+/// of the file and its folders, then its SHA-256 and signature if configured.
+///
+/// Return the opened plugin, to be loaded from this very file, and its SHA-256 (hex).
+pub fn verify_plugin(
+    path: &Path,
+    integrity: Option<&IntegrityConfig>,
+) -> Result<(File, String), Error> {
+    let mut file = open_plugin_file(path)?;
+    let meta = file.metadata()?;
+    if !meta.is_file() {
+        return Err(Error::new(ErrorKind::InvalidInput, "not a file"));
+    }
+    check_owner(path, &meta)?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    let digest = to_hex(&sha256(&content));
+
+    let integrity = match integrity {
+        Some(integrity) => integrity,
+        None => return Ok((file, digest)),
+    };
+    if let Some(allowlist) = &integrity.sha256 {
+        if !allowlist
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(&digest))
+        {
+            return Err(Error::new(
+                ErrorKind::PermissionDenied,
+                format!("sha256 {} is not in the allowlist", digest),
+            ));
+        }
+    }
+    if let Some(public_key) = &integrity.public_key {
+        let signature = fs::read(signature_path(path))
+            .map_err(|err| Error::new(err.kind(), format!("cannot read the signature: {}", err)))?;
+        verify_signature(public_key, &content, &signature)?;
+    }
+    Ok((file, digest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{signature_path, verify_plugin};
+    use crate::options::IntegrityConfig;
+
+    use openssl::{pkey::PKey, sign::Signer};
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    #[test]
+    fn verify_plugins() {
+        let folder = std::env::temp_dir().join("speculare_integrity");
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir(&folder).unwrap();
+        fs::set_permissions(&folder, fs::Permissions::from_mode(0o755)).unwrap();
+        let plugin = folder.join("plugin.so");
+        fs::write(&plugin, "plugin").unwrap();
+        fs::set_permissions(&plugin, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let (_, digest) = verify_plugin(&plugin, None).unwrap();
+        assert_eq!(
+            digest,
+            "5e689e2b01672bf33996e75d5e372ff60c536ce1599a1458e867cd8f4bef5160"
+        );
+        let mut integrity = IntegrityConfig {
+            sha256: Some(vec![digest.to_uppercase()]),
+            public_key: None,
+        };
+        assert!(verify_plugin(&plugin, Some(&integrity)).is_ok());
+        integrity.sha256 = Some(vec!["00".to_owned()]);
+        assert!(verify_plugin(&plugin, Some(&integrity)).is_err());
+
+        // Detached signature
+        let key = PKey::generate_ed25519().unwrap();
+        let public_key = folder.join("key.pem");
+        fs::write(&public_key, key.public_key_to_pem().unwrap()).unwrap();
+        integrity.sha256 = None;
+        integrity.public_key = Some(public_key.to_string_lossy().into_owned());
+        assert!(verify_plugin(&plugin, Some(&integrity)).is_err());
+        let signature = Signer::new_without_digest(&key)
+            .unwrap()
+            .sign_oneshot_to_vec(b"plugin")
+            .unwrap();
+        fs::write(signature_path(&plugin), &signature).unwrap();
+        assert!(verify_plugin(&plugin, Some(&integrity)).is_ok());
+        fs::write(&plugin, "tampered").unwrap();
+        assert!(verify_plugin(&plugin, Some(&integrity)).is_err());
+
+        // Symlinks are never followed
+        let link = folder.join("link.so");
+        std::os::unix::fs::symlink(&plugin, &link).unwrap();
+        assert!(verify_plugin(&plugin, None).is_ok());
+        assert!(verify_plugin(&link, None).is_err());
+
+        // Writable by others, or in a folder which is
+        fs::set_permissions(&plugin, fs::Permissions::from_mode(0o666)).unwrap();
+        assert!(verify_plugin(&plugin, None).is_err());
+        let nested = folder.join("nested");
+        fs::create_dir(&nested).unwrap();
+        fs::set_permissions(&nested, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(nested.join("plugin.so"), "plugin").unwrap();
+        fs::set_permissions(nested.join("plugin.so"), fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(verify_plugin(&nested.join("plugin.so"), None).is_ok());
+        fs::set_permissions(&folder, fs::Permissions::from_mode(0o777)).unwrap();
+        assert!(verify_plugin(&nested.join("plugin.so"), None).is_err());
+        let _ = fs::remove_dir_all(folder);
+    }
+}
@@ -0,0 +1,685 @@
+use super::{
+    plugin_integrity::verify_plugin,
+    plugins_init::{
+        check_plugin_name, open_plugin, plugin_config, plugin_file_disabled, plugin_settings,
+    },
+    Config, IntegrityConfig, SandboxConfig,
+};
+use clap::ArgMatches;
+use serde_json::{json, Value};
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, BufRead, BufReader, Error, ErrorKind, Write},
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        process::CommandExt,
+    },
+    path::{Path, PathBuf},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long the plugin host has to start, load the plugin and init it.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Delay before restarting a crashed host, doubled each time it crashes again.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(300);
+
+/// A plugin host process, talking one JSON object per line over its stdin/stdout.
+struct PluginHost {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>,
+}
+
+/// A plugin running in its own restricted process, restarted if it crashes or hangs.
+pub struct SandboxedPlugin {
+    name: String,
+    pub version: String,
+    path: PathBuf,
+    sandbox: SandboxConfig,
+    // Checked again before each restart, the file might have been replaced
+    integrity: Option<IntegrityConfig>,
+    // Settings (as JSON) the plugin is initialized with on each (re)start
+    settings: String,
+    timeout: Duration,
+    host: Option<PluginHost>,
+    // Until a collect succeeds again, wait restart_delay between two restarts
+    restart_delay: Duration,
+    next_restart: Option<Instant>,
+}
+
+/// The agent binary, which serves the `plugin-host` subcommand.
+#[cfg(not(test))]
+fn host_exe() -> Result<PathBuf, Error> {
+    std::env::current_exe()
+}
+
+/// The unit tests run from target/<profile>/deps, next to the agent binary.
+#[cfg(test)]
+fn host_exe() -> Result<PathBuf, Error> {
+    let exe = std::env::current_exe()?;
+    let dir = exe
+        .parent()
+        .and_then(Path::parent)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "no target folder"))?;
+    Ok(dir.join(format!("speculare-client{}", std::env::consts::EXE_SUFFIX)))
+}
+
+impl PluginHost {
+    /// Spawn the `plugin-host` of the plugin `file`, verified from `path`, and wait
+    /// for its name and version.
+    fn start(
+        path: &Path,
+        file: &File,
+        sandbox: &SandboxConfig,
+    ) -> Result<(Self, String, String), Error> {
+        let fd = file.as_raw_fd();
+        let mut command = Command::new(host_exe()?);
+        command
+            .arg("plugin-host")
+            .arg("--plugin")
+            .arg(path)
+            .arg("--fd")
+            .arg(fd.to_string());
+        // The host loads the plugin from the very file the agent verified.
+        // Safety: fcntl is async-signal-safe.
+        unsafe {
+            command.pre_exec(move || {
+                if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                    return Err(Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        if let Some(user) = &sandbox.user {
+            command.arg("--user").arg(user);
+        }
+        for (flag, limit) in &[
+            ("--max-memory", sandbox.max_memory),
+            ("--max-cpu", sandbox.max_cpu),
+            ("--max-files", sandbox.max_files),
+        ] {
+            if let Some(limit) = limit {
+                command.arg(flag).arg(limit.to_string());
+            }
+        }
+        if !sandbox.seccomp.unwrap_or(true) {
+            command.arg("--no-seccomp");
+        }
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()?;
+
+        let stdin = child.stdin.take();
+        let stdout = child.stdout.take();
+        let (stdin, stdout) = match (stdin, stdout) {
+            (Some(stdin), Some(stdout)) => (stdin, stdout),
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Error::new(ErrorKind::Other, "no stdin/stdout"));
+            }
+        };
+        // Read the replies in the background so a hung host can be timed out
+        let (tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let sent = match line {
+                    Ok(line) => tx.send(line).is_ok(),
+                    Err(_) => false,
+                };
+                if !sent {
+                    break;
+                }
+            }
+        });
+
+        let mut host = PluginHost {
+            child,
+            stdin,
+            lines,
+        };
+        let hello = host.request(None, HANDSHAKE_TIMEOUT)?;
+        let name = hello["name"]
+            .as_str()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "no plugin name"))?
+            .to_owned();
+        let version = hello["version"].as_str().unwrap_or_default().to_owned();
+        Ok((host, name, version))
+    }
+
+    /// Init the plugin with its settings (as JSON).
+    fn init(&mut self, settings: &str) -> Result<(), Error> {
+        self.request(Some(settings), HANDSHAKE_TIMEOUT)
+            .map(|_| ())
+            .map_err(|err| Error::new(err.kind(), format!("init {}", err)))
+    }
+
+    /// Send `request` (if any) and wait for the reply, at most `timeout`.
+    fn request(&mut self, request: Option<&str>, timeout: Duration) -> Result<Value, Error> {
+        if let Some(request) = request {
+            writeln!(self.stdin, "{}", request)?;
+            self.stdin.flush()?;
+        }
+        let line = self.lines.recv_timeout(timeout).map_err(|err| match err {
+            RecvTimeoutError::Timeout => Error::new(ErrorKind::TimedOut, "timed out"),
+            RecvTimeoutError::Disconnected => {
+                Error::new(ErrorKind::UnexpectedEof, "the plugin host exited")
+            }
+        })?;
+        let reply: Value = serde_json::from_str(&line).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid reply from the plugin host: {}", err),
+            )
+        })?;
+        match reply.get("error") {
+            Some(err) => Err(Error::new(
+                ErrorKind::Other,
+                err.as_str().unwrap_or("unknown error").to_owned(),
+            )),
+            None => Ok(reply),
+        }
+    }
+}
+
+impl Drop for PluginHost {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl SandboxedPlugin {
+    /// Start the plugin at `path` in its host, None if it's disabled.
+    ///
+    /// The plugin is rejected before its init if its name is one of `taken`.
+    pub fn start(
+        path: &Path,
+        config: &Config,
+        sandbox: &SandboxConfig,
+        taken: &[String],
+    ) -> Result<Option<(String, Self)>, Error> {
+        if plugin_file_disabled(config.plugins.as_ref(), path) {
+            return Ok(None);
+        }
+        let (file, digest) = verify_plugin(path, config.plugins_integrity.as_ref())?;
+        debug!("plugin ({:?}) verified, sha256 {}", path, digest);
+        let (mut host, name, version) = PluginHost::start(path, &file, sandbox)?;
+        check_plugin_name(&name, taken)?;
+        let settings = match plugin_settings(config.plugins.as_ref(), &name) {
+            Some(settings) => settings,
+            None => return Ok(None),
+        };
+        host.init(&settings)?;
+        info!("plugin {} v{} initialized in its sandbox", name, version);
+
+        let timeout = plugin_config(config, &name)
+            .and_then(|plugin_config| plugin_config.timeout)
+            .or(config.plugins_timeout)
+            .unwrap_or(10);
+        Ok(Some((
+            name.to_owned(),
+            SandboxedPlugin {
+                name,
+                version,
+                path: path.to_owned(),
+                sandbox: sandbox.clone(),
+                integrity: config.plugins_integrity.clone(),
+                settings,
+                timeout: Duration::from_secs(timeout),
+                host: Some(host),
+                restart_delay: RESTART_DELAY,
+                next_restart: None,
+            },
+        )))
+    }
+
+    /// Start a new host after the previous one crashed or hung.
+    fn restart(&mut self) -> Result<PluginHost, Error> {
+        warn!("restarting the host of the plugin {}", self.name);
+        let (file, _) = verify_plugin(&self.path, self.integrity.as_ref())?;
+        let (mut host, name, version) = PluginHost::start(&self.path, &file, &self.sandbox)?;
+        if name != self.name {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{:?} is now the plugin {}", self.path, name),
+            ));
+        }
+        host.init(&self.settings)?;
+        self.version = version;
+        Ok(host)
+    }
+
+    /// Wait longer before the next restart.
+    fn back_off(&mut self) {
+        self.next_restart = Some(Instant::now() + self.restart_delay);
+        self.restart_delay = (self.restart_delay * 2).min(MAX_RESTART_DELAY);
+    }
+
+    /// Ask the host to collect the plugin.
+    pub fn collect(&mut self) -> Result<Value, Error> {
+        let host = match self.host.take() {
+            Some(host) => host,
+            None => {
+                if let Some(next_restart) = self.next_restart {
+                    let now = Instant::now();
+                    if now < next_restart {
+                        return Err(Error::new(
+                            ErrorKind::Other,
+                            format!("the plugin host restarts in {:?}", next_restart - now),
+                        ));
+                    }
+                }
+                match self.restart() {
+                    Ok(host) => host,
+                    Err(err) => {
+                        self.back_off();
+                        return Err(err);
+                    }
+                }
+            }
+        };
+        let timeout = self.timeout;
+        let host = self.host.get_or_insert(host);
+        match host.request(Some("collect"), timeout) {
+            Ok(mut reply) => {
+                self.restart_delay = RESTART_DELAY;
+                self.next_restart = None;
+                Ok(reply["ok"].take())
+            }
+            Err(err) => {
+                // Errors of the plugin are reported as is, the other ones mean
+                // the host crashed or hung: kill it, it's restarted later.
+                if err.kind() != ErrorKind::Other {
+                    self.host = None;
+                    self.back_off();
+                }
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Keep the real stdout for the replies, and redirect what the plugin prints to stderr.
+fn take_stdout() -> Result<File, Error> {
+    let fd = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if fd < 0 || unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        return Err(Error::last_os_error());
+    }
+    // Safety: fd was just duplicated and is owned by nobody else
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Switch to `user` and its primary group (the agent must be root).
+fn drop_privileges(user: &str) -> Result<(), Error> {
+    let c_user = CString::new(user)?;
+    let pwd = unsafe { libc::getpwnam(c_user.as_ptr()) };
+    if pwd.is_null() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("unknown user {}", user),
+        ));
+    }
+    let (uid, gid) = unsafe { ((*pwd).pw_uid, (*pwd).pw_gid) };
+    // The groups can't be changed anymore once the uid is dropped
+    if unsafe { libc::setgroups(1, &gid) } != 0
+        || unsafe { libc::setgid(gid) } != 0
+        || unsafe { libc::setuid(uid) } != 0
+    {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn set_no_new_privs() -> Result<(), Error> {
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_no_new_privs() -> Result<(), Error> {
+    Err(Error::new(
+        ErrorKind::Other,
+        "no_new_privs is only supported on Linux",
+    ))
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+mod seccomp {
+    use std::io::Error;
+
+    /// struct sock_filter of linux/filter.h
+    #[repr(C)]
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct SockFilter {
+        pub code: u16,
+        pub jt: u8,
+        pub jf: u8,
+        pub k: u32,
+    }
+
+    /// struct sock_fprog of linux/filter.h
+    #[repr(C)]
+    struct SockFprog {
+        len: u16,
+        filter: *const SockFilter,
+    }
+
+    pub const BPF_LD_W_ABS: u16 = 0x20;
+    pub const BPF_JEQ_K: u16 = 0x15;
+    pub const BPF_JGE_K: u16 = 0x35;
+    pub const BPF_RET_K: u16 = 0x06;
+    pub const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+    pub const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+    const SECCOMP_SET_MODE_FILTER: libc::c_long = 1;
+    const SECCOMP_FILTER_FLAG_TSYNC: libc::c_long = 1;
+    // Offsets of nr and arch in struct seccomp_data
+    const DATA_NR: u32 = 0;
+    const DATA_ARCH: u32 = 4;
+    // Syscalls of the x32 ABI, which share the x86_64 arch
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xC000_003E;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    /// Syscalls a plugin has no business making.
+    const DENIED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_setns,
+        libc::SYS_unshare,
+        libc::SYS_reboot,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_swapon,
+        libc::SYS_swapoff,
+        libc::SYS_bpf,
+        libc::SYS_perf_event_open,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_userfaultfd,
+        libc::SYS_add_key,
+        libc::SYS_keyctl,
+        libc::SYS_acct,
+        libc::SYS_quotactl,
+        libc::SYS_settimeofday,
+        libc::SYS_clock_settime,
+    ];
+
+    fn stmt(code: u16, k: u32) -> SockFilter {
+        SockFilter {
+            code,
+            jt: 0,
+            jf: 0,
+            k,
+        }
+    }
+
+    fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+        SockFilter { code, jt, jf, k }
+    }
+
+    /// Build the BPF program failing the `denied` syscalls with EPERM, as well as
+    /// the syscalls of any other ABI than `arch`.
+    pub fn program(arch: u32, denied: &[u32]) -> Vec<SockFilter> {
+        let n = denied.len();
+        // Jumps are relative to the next instruction, the last one is the deny
+        let mut prog = vec![
+            stmt(BPF_LD_W_ABS, DATA_ARCH),
+            jump(BPF_JEQ_K, arch, 0, (n + 3) as u8),
+            stmt(BPF_LD_W_ABS, DATA_NR),
+            jump(BPF_JGE_K, X32_SYSCALL_BIT, (n + 1) as u8, 0),
+        ];
+        for (idx, nr) in denied.iter().enumerate() {
+            prog.push(jump(BPF_JEQ_K, *nr, (n - idx) as u8, 0));
+        }
+        prog.push(stmt(BPF_RET_K, SECCOMP_RET_ALLOW));
+        prog.push(stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32));
+        prog
+    }
+
+    /// Install the filter on every thread of the process (requires no_new_privs).
+    pub fn install() -> Result<(), Error> {
+        let denied: Vec<u32> = DENIED_SYSCALLS.iter().map(|nr| *nr as u32).collect();
+        let filter = program(AUDIT_ARCH, &denied);
+        let prog = SockFprog {
+            len: filter.len() as u16,
+            filter: filter.as_ptr(),
+        };
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                SECCOMP_SET_MODE_FILTER,
+                SECCOMP_FILTER_FLAG_TSYNC,
+                &prog as *const SockFprog,
+            )
+        };
+        if res != 0 {
+            return Err(Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn install_seccomp() -> Result<(), Error> {
+    seccomp::install()
+}
+
+#[cfg(not(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+)))]
+fn install_seccomp() -> Result<(), Error> {
+    Err(Error::new(
+        ErrorKind::Other,
+        "seccomp is not supported on this platform",
+    ))
+}
+
+/// Apply the rlimits, user, no_new_privs and seccomp filter asked by `args`.
+fn restrict(args: &ArgMatches) -> Result<(), Error> {
+    for (resource, name, scale) in &[
+        (libc::RLIMIT_AS, "max-memory", 1024 * 1024),
+        (libc::RLIMIT_CPU, "max-cpu", 1),
+        (libc::RLIMIT_NOFILE, "max-files", 1),
+    ] {
+        if let Some(limit) = args.value_of(*name) {
+            let limit = limit
+                .parse::<u64>()
+                .map_err(|err| Error::new(ErrorKind::InvalidInput, format!("{}: {}", name, err)))?;
+            let limit = limit.checked_mul(*scale).ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, format!("{}: too large", name))
+            })?;
+            let limit = libc::rlimit {
+                rlim_cur: limit,
+                rlim_max: limit,
+            };
+            if unsafe { libc::setrlimit(*resource, &limit) } != 0 {
+                return Err(Error::last_os_error());
+            }
+        }
+    }
+    if let Some(user) = args.value_of("user") {
+        drop_privileges(user)?;
+    }
+    // Root would bypass most of the restrictions below
+    if unsafe { libc::geteuid() } == 0 {
+        return Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "refusing to run the plugin as root, set plugins_sandbox.user",
+        ));
+    }
+    set_no_new_privs()?;
+    if !args.is_present("no-seccomp") {
+        install_seccomp()?;
+    }
+    Ok(())
+}
+
+/// The verified plugin file inherited from the agent.
+fn inherited_file(args: &ArgMatches) -> Result<File, Error> {
+    let fd = args
+        .value_of("fd")
+        .and_then(|fd| fd.parse::<RawFd>().ok())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "invalid fd"))?;
+    // Safety: the agent left this fd open for the host, nothing else uses it
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+fn reply(out: &mut File, reply: &Value) -> Result<(), Error> {
+    writeln!(out, "{}", reply)?;
+    out.flush()
+}
+
+/// Entrypoint of the `plugin-host` subcommand: restrict the process, load the plugin
+/// and serve its collects over stdin/stdout until the agent goes away.
+pub fn run_plugin_host(args: &ArgMatches) -> Result<(), Error> {
+    let path = Path::new(args.value_of("plugin").unwrap_or_default());
+    let mut out = take_stdout()?;
+
+    let plugin = restrict(args)
+        .and_then(|_| inherited_file(args))
+        .and_then(|file| open_plugin(path, file));
+    let (name, mut info) = match plugin {
+        Ok(plugin) => plugin,
+        Err(err) => {
+            reply(&mut out, &json!({ "error": err.to_string() }))?;
+            return Err(err);
+        }
+    };
+    reply(&mut out, &json!({"name": name, "version": info.version}))?;
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    let settings = match lines.next() {
+        Some(settings) => settings?,
+        None => return Ok(()),
+    };
+    if let Err(err) = info.init(&settings) {
+        reply(&mut out, &json!({ "error": err.to_string() }))?;
+        return Err(err);
+    }
+    reply(&mut out, &json!({ "ok": null }))?;
+
+    for line in lines {
+        match line?.as_str() {
+            "collect" => match info.collect() {
+                Ok(val) => reply(&mut out, &json!({ "ok": val }))?,
+                Err(err) => reply(&mut out, &json!({ "error": err.to_string() }))?,
+            },
+            request => warn!("plugin host {}: unknown request {}", name, request),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{host_exe, PluginHost, SandboxedPlugin, RESTART_DELAY};
+    use crate::options::{Config, SandboxConfig};
+
+    use serde_json::json;
+    use std::{
+        env::consts::{DLL_PREFIX, DLL_SUFFIX},
+        fs::File,
+    };
+
+    #[test]
+    #[ignore = "needs the agent and the active_users plugin built (cargo build --workspace)"]
+    fn sandboxed_plugin() {
+        let plugin = host_exe()
+            .unwrap()
+            .with_file_name(format!("{}active_users{}", DLL_PREFIX, DLL_SUFFIX));
+        let config: Config = serde_json::from_value(json!({
+            "api_token": "token",
+            "api_url": "https://localhost",
+            "harvest_interval": 1,
+            "syncing_interval": 1,
+            "loadavg_interval": 1,
+            "plugins_path": "/nonexistent",
+        }))
+        .unwrap();
+        let root = unsafe { libc::geteuid() } == 0;
+        let mut sandbox = SandboxConfig {
+            user: None,
+            max_memory: Some(256),
+            max_cpu: None,
+            max_files: Some(64),
+            seccomp: None,
+        };
+        if root {
+            // The plugins never run as root
+            assert!(PluginHost::start(&plugin, &File::open(&plugin).unwrap(), &sandbox).is_err());
+            sandbox.user = Some("nobody".to_owned());
+        }
+
+        // hello, init and collect
+        let (name, mut sandboxed) = SandboxedPlugin::start(&plugin, &config, &sandbox, &[])
+            .unwrap()
+            .unwrap();
+        assert_eq!(name, "active_users");
+        assert_eq!(sandboxed.version, "0.1.0");
+        assert!(sandboxed.collect().unwrap().is_array());
+
+        // A crashed host fails its collect, and is restarted once its delay is over
+        sandboxed.host.as_mut().unwrap().child.kill().unwrap();
+        assert!(sandboxed.collect().is_err());
+        assert!(sandboxed.host.is_none());
+        let err = sandboxed.collect().unwrap_err();
+        assert!(err.to_string().starts_with("the plugin host restarts in"));
+        assert!(sandboxed.host.is_none());
+        assert_eq!(sandboxed.restart_delay, RESTART_DELAY * 2);
+        sandboxed.next_restart = None;
+        assert!(sandboxed.collect().unwrap().is_array());
+        assert!(sandboxed.host.is_some());
+        assert_eq!(sandboxed.restart_delay, RESTART_DELAY);
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn seccomp_program() {
+        use super::seccomp::*;
+
+        let prog = program(0xC000_003E, &[101, 165]);
+        assert_eq!(prog.len(), 8);
+        let deny = prog.len() - 1;
+        assert_eq!(prog[deny].code, BPF_RET_K);
+        assert_eq!(prog[deny].k, SECCOMP_RET_ERRNO | libc::EPERM as u32);
+        assert_eq!(prog[deny - 1].k, SECCOMP_RET_ALLOW);
+        // Every jump to the deny lands on it
+        for (idx, ins) in prog.iter().enumerate() {
+            if ins.code == BPF_JEQ_K && idx > 1 {
+                assert_eq!(idx + 1 + ins.jt as usize, deny);
+            }
+        }
+        assert_eq!(2 + prog[1].jf as usize, deny);
+        assert_eq!(4 + prog[3].jt as usize, deny);
+    }
+}
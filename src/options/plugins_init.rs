@@ -1,39 +1,329 @@
-use super::{Config, PluginInfo, PluginsMap};
+use super::{
+    plugin_abi::{
+        copy_c_str, AbiVersionFn, NameFn, VersionFn, ABI_VERSION, SYM_ABI_VERSION, SYM_COLLECT,
+        SYM_FREE, SYM_INIT, SYM_NAME, SYM_SHUTDOWN, SYM_VERSION,
+    },
+    plugin_integrity::verify_plugin,
+    Config, IntegrityConfig, PluginConfig, PluginInfo,
+};
 
-use std::{collections::HashMap, io::Error, io::ErrorKind};
+use std::{
+    collections::HashMap, env::consts::DLL_SUFFIX, ffi::OsStr, fs::File, io::Error, io::ErrorKind,
+    path::Path, path::PathBuf,
+};
 
-pub fn get_plugins(config: &Config) -> Result<PluginsMap, Error> {
-    let mut plugins: PluginsMap = HashMap::new();
+/// Resolve the symbols of the plugin `lib`, loaded from `file`, and return its name
+/// along with them.
+fn load_symbols(lib: libloading::Library, file: File) -> Result<(String, PluginInfo), Error> {
+    let to_err = |err: libloading::Error| Error::new(ErrorKind::InvalidData, err);
+
+    // Check the ABI version before trusting the signatures of the other symbols
+    let abi_version = unsafe { (*lib.get::<AbiVersionFn>(SYM_ABI_VERSION).map_err(to_err)?)() };
+    if abi_version != ABI_VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "ABI version {} is not supported (expected {})",
+                abi_version, ABI_VERSION
+            ),
+        ));
+    }
+
+    let name = unsafe { copy_c_str((*lib.get::<NameFn>(SYM_NAME).map_err(to_err)?)()) }
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "null plugin name"))?;
+    let version = unsafe { copy_c_str((*lib.get::<VersionFn>(SYM_VERSION).map_err(to_err)?)()) }
+        .unwrap_or_default();
+    let info = unsafe {
+        PluginInfo {
+            version,
+            init: *lib.get(SYM_INIT).map_err(to_err)?,
+            collect: *lib.get(SYM_COLLECT).map_err(to_err)?,
+            shutdown: *lib.get(SYM_SHUTDOWN).map_err(to_err)?,
+            free: *lib.get(SYM_FREE).map_err(to_err)?,
+            initialized: false,
+            lib,
+            file,
+        }
+    };
+
+    Ok((name, info))
+}
+
+/// Get the config section of the plugin `name`, if any.
+pub fn plugin_config<'a>(config: &'a Config, name: &str) -> Option<&'a PluginConfig> {
+    config.plugins.as_ref()?.get(name)
+}
+
+/// Path to load the verified `file` (opened from `path`) from.
+#[cfg(target_os = "linux")]
+fn library_path(file: &File, _path: &Path) -> PathBuf {
+    use std::os::unix::io::AsRawFd;
+
+    // The fd itself, the path might point to another file since the checks
+    PathBuf::from(format!("/proc/self/fd/{}", file.as_raw_fd()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn library_path(_file: &File, path: &Path) -> PathBuf {
+    path.to_owned()
+}
+
+/// Load the plugin `file`, verified from `path`, and resolve its symbols.
+pub(crate) fn open_plugin(path: &Path, file: File) -> Result<(String, PluginInfo), Error> {
+    if !file.metadata()?.is_file() {
+        return Err(Error::new(ErrorKind::InvalidInput, "not a file"));
+    }
+    let lib = unsafe { libloading::Library::new(library_path(&file, path)) }
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    trace!("plugin ({:?}) loaded correctly", path);
+    load_symbols(lib, file)
+}
+
+/// Check if the plugin `name` is disabled by its config section.
+fn plugin_disabled(configs: Option<&HashMap<String, PluginConfig>>, name: &str) -> bool {
+    matches!(
+        configs.and_then(|configs| configs.get(name)),
+        Some(PluginConfig {
+            enabled: Some(false),
+            ..
+        })
+    )
+}
+
+/// Get the config section of the plugin at `path` from its file name (`foo.so` or
+/// `libfoo.so` for the plugin foo), before it gets loaded.
+pub(crate) fn plugin_file_config<'a>(
+    configs: Option<&'a HashMap<String, PluginConfig>>,
+    path: &Path,
+) -> Option<&'a PluginConfig> {
+    let configs = configs?;
+    let stem = path.file_stem().and_then(OsStr::to_str)?;
+    configs
+        .get(stem)
+        .or_else(|| configs.get(stem.strip_prefix("lib")?))
+}
+
+/// Check if the plugin at `path` is disabled from its file name, so it doesn't
+/// even get loaded.
+pub(crate) fn plugin_file_disabled(
+    configs: Option<&HashMap<String, PluginConfig>>,
+    path: &Path,
+) -> bool {
+    let disabled = matches!(
+        plugin_file_config(configs, path),
+        Some(PluginConfig {
+            enabled: Some(false),
+            ..
+        })
+    );
+    if disabled {
+        info!("plugin ({:?}) is disabled", path);
+    }
+    disabled
+}
+
+/// Get the settings (as JSON) to init the plugin `name` with, None if it's disabled.
+pub(crate) fn plugin_settings(
+    configs: Option<&HashMap<String, PluginConfig>>,
+    name: &str,
+) -> Option<String> {
+    if plugin_disabled(configs, name) {
+        info!("plugin {} is disabled", name);
+        return None;
+    }
+    Some(
+        configs
+            .and_then(|configs| configs.get(name))
+            .and_then(|plugin_config| plugin_config.settings.as_ref())
+            .map_or_else(|| "null".to_owned(), |settings| settings.to_string()),
+    )
+}
+
+/// Check that no other plugin (loaded or exec) is already named `name`.
+pub(crate) fn check_plugin_name(name: &str, taken: &[String]) -> Result<(), Error> {
+    if taken.iter().any(|taken| taken == name) {
+        return Err(Error::new(
+            ErrorKind::AlreadyExists,
+            format!("a plugin named {} is already running", name),
+        ));
+    }
+    Ok(())
+}
+
+/// Verify, load and initialize the plugin at `path` with its settings, None if it's disabled.
+///
+/// The plugin is rejected before its init if its name is one of `taken`.
+pub(crate) fn load_plugin(
+    path: &Path,
+    configs: Option<&HashMap<String, PluginConfig>>,
+    integrity: Option<&IntegrityConfig>,
+    taken: &[String],
+) -> Result<Option<(String, PluginInfo)>, Error> {
+    if plugin_file_disabled(configs, path) {
+        return Ok(None);
+    }
+    let (file, digest) = verify_plugin(path, integrity)?;
+    debug!("plugin ({:?}) verified, sha256 {}", path, digest);
+    let (name, mut info) = open_plugin(path, file)?;
+    check_plugin_name(&name, taken)?;
+    let settings = match plugin_settings(configs, &name) {
+        Some(settings) => settings,
+        None => return Ok(None),
+    };
+    info.init(&settings)
+        .map_err(|err| Error::new(err.kind(), format!("init {}", err)))?;
+    Ok(Some((name, info)))
+}
+
+/// Check if `path` is named like a plugin (`*.so` on Linux), the hidden files
+/// like the temporary ones of an install are not.
+pub(crate) fn is_plugin_file(path: &Path) -> bool {
+    match path.file_name().and_then(OsStr::to_str) {
+        Some(name) => !name.starts_with('.') && name.ends_with(DLL_SUFFIX),
+        None => false,
+    }
+}
+
+/// List the plugins of the plugins_path, the unreadable entries and the other
+/// files (like the signatures of the plugins) are skipped.
+pub(crate) fn plugin_paths(config: &Config) -> Result<Vec<PathBuf>, Error> {
     let paths = std::fs::read_dir(&config.plugins_path)?;
     trace!("successfully read the plugins folder");
-    for path in paths {
-        // TODO - Get rid of unsafe unwrap
-        let path = path.unwrap();
-        debug!("is {:?} a plugin", path.path());
-        let lib = match unsafe { libloading::Library::new(path.path()) } {
-            Ok(library) => {
-                trace!("plugin ({:?}) loaded correctly", path.file_name());
-                library
-            }
-            Err(err_lib) => {
-                error!(
-                    "plugin ({:?}) failed to load: {:?}",
-                    path.file_name(),
-                    err_lib
-                );
-                continue;
+    Ok(paths
+        .filter_map(|path| match path {
+            Ok(path) => Some(path.path()).filter(|path| is_plugin_file(path)),
+            Err(err) => {
+                error!("cannot read the plugins folder entry: {}", err);
+                None
             }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        is_plugin_file, load_plugin, plugin_config, plugin_file_disabled, plugin_settings,
+        ABI_VERSION,
+    };
+    use crate::options::{Config, PluginConfig};
+
+    use serde_json::json;
+    use std::{
+        collections::HashMap,
+        env::consts::{DLL_PREFIX, DLL_SUFFIX},
+        io::{Error, ErrorKind},
+        path::Path,
+    };
+
+    /// Load the plugin at `path`, which must be rejected.
+    fn rejected(path: &Path) -> Error {
+        match load_plugin(path, None, None, &[]) {
+            Ok(_) => panic!("{:?} was loaded", path),
+            Err(err) => err,
+        }
+    }
+
+    #[test]
+    fn reject_invalid_plugins() {
+        let readme =
+            std::env::temp_dir().join(format!("speculare_plugins_README_{}", std::process::id()));
+        std::fs::write(&readme, "not a plugin").unwrap();
+        assert_eq!(rejected(&readme).kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            rejected(&std::env::temp_dir()).kind(),
+            ErrorKind::InvalidInput
+        );
+        assert_eq!(
+            rejected(Path::new("/nonexistent/plugin.so")).kind(),
+            ErrorKind::NotFound
+        );
+        let _ = std::fs::remove_file(readme);
+    }
+
+    #[test]
+    #[ignore = "needs the fixture plugins built (cargo build --workspace)"]
+    fn reject_invalid_symbols() {
+        let exe = std::env::current_exe().unwrap();
+        let fixture = |name: &str| {
+            exe.parent()
+                .and_then(Path::parent)
+                .unwrap()
+                .join(format!("{}fixture_{}{}", DLL_PREFIX, name, DLL_SUFFIX))
         };
-        // TODO - Get rid of unsafe unwrap
-        let info: fn() -> String = *(unsafe { lib.get(b"info") }.unwrap());
-        let func: fn() -> Result<String, Error> = *(unsafe { lib.get(b"entrypoint") }.unwrap());
-        plugins.insert(info(), PluginInfo { lib, func });
-    }
-    // Return the PluginsMap if there are some
-    // Else return an error (NotFound)
-    if !plugins.is_empty() {
-        Ok(plugins)
-    } else {
-        Err(Error::new(ErrorKind::NotFound, "no plugins found"))
+
+        let err = rejected(&fixture("bad_abi"));
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "ABI version {} is not supported (expected {})",
+                ABI_VERSION + 1,
+                ABI_VERSION
+            )
+        );
+        let err = rejected(&fixture("no_collect"));
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("speculare_plugin_collect"));
+    }
+
+    #[test]
+    fn plugin_files() {
+        let plugin = format!("active_users{}", std::env::consts::DLL_SUFFIX);
+        assert!(is_plugin_file(Path::new(&plugin)));
+        assert!(is_plugin_file(&Path::new("/plugins").join(&plugin)));
+        assert!(!is_plugin_file(Path::new(&format!(".{}", plugin))));
+        assert!(!is_plugin_file(Path::new(&format!("{}.sig", plugin))));
+        assert!(!is_plugin_file(Path::new(".active_users.tmp")));
+        assert!(!is_plugin_file(Path::new("README")));
+    }
+
+    #[test]
+    fn plugins_config() {
+        let config: Config = serde_json::from_value(json!({
+            "api_token": "token",
+            "api_url": "https://localhost",
+            "harvest_interval": 1,
+            "syncing_interval": 1,
+            "loadavg_interval": 1,
+            "plugins_path": "/nonexistent",
+            "plugins": {
+                "active_users": {"interval": 5, "settings": {"users": ["root"]}},
+                "disabled": {"enabled": false}
+            }
+        }))
+        .unwrap();
+        let configs: Option<&HashMap<String, PluginConfig>> = config.plugins.as_ref();
+
+        assert_eq!(
+            plugin_config(&config, "active_users").unwrap().interval,
+            Some(5)
+        );
+        assert!(plugin_config(&config, "other").is_none());
+        assert_eq!(
+            plugin_settings(configs, "active_users").unwrap(),
+            r#"{"users":["root"]}"#
+        );
+        assert_eq!(plugin_settings(configs, "other").unwrap(), "null");
+        assert!(plugin_settings(configs, "disabled").is_none());
+
+        // Disabled plugins are skipped before even looking at the file
+        assert!(plugin_file_disabled(
+            configs,
+            Path::new("/nonexistent/libdisabled.so")
+        ));
+        assert!(plugin_file_disabled(
+            configs,
+            Path::new("/nonexistent/disabled.so")
+        ));
+        assert!(!plugin_file_disabled(
+            configs,
+            Path::new("/nonexistent/libother.so")
+        ));
+        assert!(
+            load_plugin(Path::new("/nonexistent/libdisabled.so"), configs, None, &[])
+                .unwrap()
+                .is_none()
+        );
     }
 }